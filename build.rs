@@ -1,54 +1,206 @@
-#![feature(path_ext)]
 #![feature(path_relative_from)]
 
 extern crate phf_codegen;
 extern crate glob;
 extern crate uuid;
+extern crate flate2;
 
+use std::env;
 use std::fs;
+use std::hash::{Hasher, SipHasher};
 use std::io::prelude::*;
-use std::path::Path;
-use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::MetadataExt;
+use std::collections::{BTreeMap, BTreeSet};
 
 fn main() {
     println!("cargo:rustc-link-search=native={}", "cpython/Modules/zlib");
 
-    let paths: Vec<String> = glob::glob("include/**/*").unwrap()
-        .map(|e| e.unwrap())
-        .filter(|p| p.is_file())
-        .map(|p| String::from(p.relative_from("include").unwrap().to_str().unwrap()))
+    // include/ is served at the workdir and keyed by paths relative to it.
+    // PYINRS_MOUNTS adds more source dirs, as colon separated srcdir=/mount
+    // pairs, keyed by their absolute virtual paths.
+    let mut mounts = vec![(String::from("include"), String::from(""))];
+    for spec in env::var("PYINRS_MOUNTS").unwrap_or(String::new()).split(':').filter(|s| *s != "") {
+        let mut parts = spec.splitn(2, '=');
+        let srcdir = parts.next().unwrap();
+        let mountpath = parts.next().expect("PYINRS_MOUNTS entries must be srcdir=/mount/path");
+        let mountpath = mountpath.trim_right_matches('/');
+        assert!(mountpath.starts_with("/"), "mount path {} must be absolute", mountpath);
+        mounts.push((String::from(srcdir), String::from(mountpath)));
+    }
+
+    // srcpath, key, mount root, link target if a symlink
+    let mut paths: Vec<(String, String, &str, Option<String>)> = vec![];
+    for &(ref srcdir, ref mountpath) in &mounts {
+        let mut srcpaths = vec![];
+        walk(Path::new(srcdir), &mut srcpaths);
+        for srcpath in srcpaths {
+            let relpath = srcpath.relative_from(srcdir).unwrap().to_str().unwrap();
+            let key = if mountpath.is_empty() { String::from(relpath) } else { format!("{}/{}", mountpath, relpath) };
+            let target = fs::read_link(&srcpath).ok().map(|t| String::from(t.to_str().unwrap()));
+            paths.push((String::from(srcpath.to_str().unwrap()), key, mountpath, target));
+        }
+    }
+
+    // Files are stored zlib compressed unless matching one of these globs
+    // (colon separated), or compression doesn't make them any smaller
+    let no_compress: Vec<glob::Pattern> = env::var("PYINRS_NO_COMPRESS")
+        .unwrap_or(String::from("*.zip:*.gz:*.bz2:*.xz"))
+        .split(':')
+        .filter(|p| *p != "")
+        .map(|p| glob::Pattern::new(p).unwrap())
         .collect();
+    let _ = fs::remove_dir_all("include.z");
+    fs::create_dir("include.z").unwrap();
 
     // TODO: make files and dirs an enum in the same map?
     let mut file = fs::File::create("include.files").unwrap();
     let mut filebuilder = phf_codegen::Map::new();
-    let mut dirset = HashSet::new();
+    let mut linkbuilder = phf_codegen::Map::new();
+    // dir -> (child relpath, isdir), so listings don't have to search
+    let mut dirmap: BTreeMap<String, BTreeSet<(String, bool)>> = BTreeMap::new();
+    // dir -> mtime, permission bits
+    let mut dirmeta: BTreeMap<String, (i64, u32)> = BTreeMap::new();
+    // Identifies the payload, so the default root only changes along with it
+    let mut payload_hasher = SipHasher::new();
+    for &(ref srcdir, ref mountpath) in &mounts {
+        dirmap.insert(mountpath.clone(), BTreeSet::new());
+        dirmeta.insert(mountpath.clone(), file_meta(Path::new(srcdir)));
+    }
 
-    for path in &paths {
+    for &(ref srcpath, ref path, mountpath, ref target) in &paths {
+        if let Some(ref target) = *target {
+            // Targets are kept as written, they're resolved when looked up
+            payload_hasher.write(path.as_bytes());
+            payload_hasher.write(&[1]);
+            payload_hasher.write(target.as_bytes());
+            let mtime = fs::symlink_metadata(srcpath).unwrap().mtime() as i64;
+            linkbuilder.entry(&**path, &format!("LinkEntry {{ target: {:?}, mtime: {} }}", target, mtime));
+            add_to_parents(&mut dirmap, &mut dirmeta, srcpath, path, mountpath);
+            continue
+        }
         write!(&mut file, "#[allow(non_upper_case_globals)]\n").unwrap();
         let varname = format!("FILE_{}", uuid::Uuid::new_v4().to_simple_string());
-        let incstr = format!("include_bytes!(\"../include/{}\")", path);
+        let mut data = vec![];
+        fs::File::open(srcpath).unwrap().read_to_end(&mut data).unwrap();
+        payload_hasher.write(path.as_bytes());
+        payload_hasher.write(&[0]);
+        payload_hasher.write(&data);
+        let compressed = if no_compress.iter().any(|p| p.matches(path)) {
+            None
+        } else {
+            let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::Best);
+            encoder.write_all(&data).unwrap();
+            let cdata = encoder.finish().unwrap();
+            if cdata.len() < data.len() { Some(cdata) } else { None }
+        };
+        let incstr = match compressed {
+            Some(ref cdata) => {
+                fs::File::create(format!("include.z/{}", varname)).unwrap().write_all(cdata).unwrap();
+                format!("include_bytes!(\"../include.z/{}\")", varname)
+            },
+            None if Path::new(srcpath).is_absolute() => format!("include_bytes!({:?})", srcpath),
+            None => format!("include_bytes!(\"../{}\")", srcpath),
+        };
         write!(&mut file, "const {}: &'static [u8] = {};\n", varname, incstr).unwrap();
-        filebuilder.entry(&**path, &varname);
-
-        let mut parent = Path::new(path).parent().unwrap();
-        while parent.to_str().unwrap() != "" {
-            dirset.insert(parent.to_str().unwrap());
-            parent = parent.parent().unwrap();
-        }
+        let (mtime, mode) = file_meta(Path::new(srcpath));
+        let entry = format!("FileEntry {{ data: {}, size: {}, compressed: {}, mtime: {}, mode: {:#o} }}",
+                            varname, data.len(), compressed.is_some(), mtime, mode);
+        filebuilder.entry(&**path, &entry);
+        add_to_parents(&mut dirmap, &mut dirmeta, srcpath, path, mountpath);
     }
 
-    let mut dirbuilder = phf_codegen::Set::new();
-    dirbuilder.entry("");
-    for dir in dirset.iter() {
-        dirbuilder.entry(dir);
+    let mut dirbuilder = phf_codegen::Map::new();
+    let childstrs: Vec<String> = dirmap.iter()
+        .map(|(dir, children)| {
+            let entries: Vec<String> = children.iter()
+                .map(|&(ref child, isdir)| format!("({:?}, {})", child, isdir))
+                .collect();
+            let (mtime, mode) = dirmeta[dir];
+            format!("DirEntry {{ children: &[{}], mtime: {}, mode: {:#o} }}", entries.join(", "), mtime, mode)
+        })
+        .collect();
+    for (dir, childstr) in dirmap.keys().zip(childstrs.iter()) {
+        dirbuilder.entry(&**dir, childstr);
     }
 
-    write!(&mut file, "static FILES: phf::Map<&'static str, &'static [u8]> = ").unwrap();
+    // The workdir everything in include/ is served at, unless overridden
+    // when running. PYINRS_ROOT sets it, with {hash} standing for the
+    // payload hash.
+    let payload_hash = format!("{:016x}", payload_hasher.finish());
+    let root = env::var("PYINRS_ROOT").unwrap_or(String::from("/tmp/pyinrs-{hash}"))
+        .replace("{hash}", &payload_hash);
+    assert!(root.starts_with("/"), "root {} must be absolute", root);
+    let root = root.trim_right_matches('/');
+    assert!(root != "", "root can't be /");
+    assert!(!root[1..].split('/').any(|part| part == "" || part == "." || part == ".."),
+            "root {} must be normalised", root);
+    write!(&mut file, "static ROOT: &'static str = {:?};\n", root).unwrap();
+    write!(&mut file, "static PAYLOAD_HASH: &'static str = {:?};\n", payload_hash).unwrap();
+
+    // What to run, see shutit.manifest
+    let manifest_path = env::var("PYINRS_MANIFEST").unwrap_or(String::from("shutit.manifest"));
+    let mut manifest = String::new();
+    fs::File::open(&manifest_path).unwrap().read_to_string(&mut manifest).unwrap();
+    check_manifest(&manifest_path, &manifest, |key| paths.iter().any(|p| Path::new(&p.1) == Path::new(key)));
+    write!(&mut file, "static MANIFEST: &'static str = {:?};\n", manifest).unwrap();
+
+    let mountstrs: Vec<String> = mounts[1..].iter().map(|&(_, ref m)| format!("{:?}", m)).collect();
+    write!(&mut file, "static MOUNTS: &'static [&'static str] = &[{}];\n", mountstrs.join(", ")).unwrap();
+
+    write!(&mut file, "static FILES: phf::Map<&'static str, FileEntry> = ").unwrap();
     filebuilder.build(&mut file).unwrap();
     write!(&mut file, ";\n").unwrap();
 
-    write!(&mut file, "static DIRS: phf::Set<&'static str> = ").unwrap();
+    write!(&mut file, "static DIRS: phf::Map<&'static str, DirEntry> = ").unwrap();
     dirbuilder.build(&mut file).unwrap();
     write!(&mut file, ";\n").unwrap();
+
+    write!(&mut file, "static LINKS: phf::Map<&'static str, LinkEntry> = ").unwrap();
+    linkbuilder.build(&mut file).unwrap();
+    write!(&mut file, ";\n").unwrap();
+}
+
+include!("src/check_manifest.rs");
+
+// Collects the files and symlinks under dir, without following links so they
+// can be embedded as links
+fn walk(dir: &Path, out: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+    entries.sort();
+    for path in entries {
+        let meta = fs::symlink_metadata(&path).unwrap();
+        if meta.file_type().is_symlink() || meta.is_file() {
+            out.push(path)
+        } else if meta.is_dir() {
+            walk(&path, out)
+        }
+    }
+}
+
+// Lists a file or link in its parent, and any newly seen parents in theirs
+fn add_to_parents(dirmap: &mut BTreeMap<String, BTreeSet<(String, bool)>>,
+                  dirmeta: &mut BTreeMap<String, (i64, u32)>,
+                  srcpath: &str, path: &str, mountpath: &str) {
+    let mut child = (String::from(path), false);
+    let mut parent = Path::new(path).parent().unwrap();
+    let mut srcparent = Path::new(srcpath).parent().unwrap();
+    loop {
+        let parent_str = String::from(parent.to_str().unwrap());
+        let is_new = !dirmap.contains_key(&parent_str);
+        dirmap.entry(parent_str.clone()).or_insert(BTreeSet::new()).insert(child);
+        if !is_new || parent_str == mountpath {
+            break
+        }
+        dirmeta.insert(parent_str.clone(), file_meta(srcparent));
+        child = (parent_str, true);
+        parent = parent.parent().unwrap();
+        srcparent = srcparent.parent().unwrap();
+    }
+}
+
+// mtime, permission bits
+fn file_meta(path: &Path) -> (i64, u32) {
+    let meta = fs::metadata(path).unwrap();
+    (meta.mtime() as i64, meta.mode() as u32 & 0o7777)
 }
@@ -18,7 +18,6 @@ cd include
         shift
         find "$loc" '!' '(' -type d -name context -prune ')' $@
     }
-    find_no_context library -type d -name bin -prune -exec rm -r '{}' ';'
     find_no_context . -type f -name '*.md' -exec rm '{}' ';'
     find_no_context . -type f -name 'Dockerfile' -exec rm '{}' ';'
     find_no_context . -type f -name 'STOPTEST' -exec rm '{}' ';'
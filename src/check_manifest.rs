@@ -0,0 +1,29 @@
+// Included by build.rs, and by manifest.rs for its tests
+
+// Catches mistakes at build time, manifest.rs trusts what it's given.
+// embedded says whether there's a file or link at a key.
+fn check_manifest<F>(path: &str, manifest: &str, embedded: F) where F: Fn(&str) -> bool {
+    // entries, scripts, code lines
+    let (mut entries, mut scripts, mut code) = (0, 0, 0);
+    for line in manifest.lines().map(|l| l.trim_right()).filter(|l| *l != "" && !l.trim_left().starts_with("#")) {
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap().trim();
+        let value = parts.next().expect(&format!("{}: expected key = value, got {}", path, line)).trim();
+        match key {
+            "path" | "hook" => (),
+            "entry" => {
+                assert!(value.contains(":"), "{}: entry must be module:function, got {}", path, value);
+                entries += 1
+            },
+            "script" => {
+                assert!(embedded(value), "{}: script {} isn't in the payload", path, value);
+                scripts += 1
+            },
+            "code" => code += 1,
+            _ => panic!("{}: unknown key {}", path, key),
+        }
+    }
+    let runs = (entries, scripts, code);
+    assert!(runs == (1, 0, 0) || runs == (0, 1, 0) || (entries == 0 && scripts == 0 && code > 0),
+            "{}: needs one entry, one script or some code", path);
+}
@@ -1,23 +1,132 @@
 use std::fs;
 use std::fs::File;
-use std::path::Path;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::{symlink, PermissionsExt};
 use std::io::prelude::*;
+use std::sync::Arc;
+use libc;
+use libc::{c_int, c_char};
 
-use super::{FILES, WORKDIR};
+use super::{FILES, DIRS, LINKS, WORKDIR, PAYLOAD_HASH, key_path};
+use super::store;
+use super::store::Contents;
 
+// Records which payload a workdir was extracted from
+const MARKER: &'static str = ".pyinrs-payload";
+
+// The workdir is left in place at exit and reused by later runs of the same
+// payload, told apart by the marker written into it. A tree from any other
+// payload (possible when PYINRS_ROOT has no {hash}) is replaced. Extraction
+// goes to a temporary directory renamed into place, so a run never sees a
+// partial tree from another one.
 pub fn prep() {
     let workdir = unsafe { WORKDIR };
-    if !Path::new(workdir).is_dir() {
-        for (relpath, data) in FILES.entries() {
-            let path = Path::new(workdir).join(relpath);
-            fs::create_dir_all(path.parent().unwrap()).unwrap();
-            let mut f = File::create(path).unwrap();
-            f.write_all(data).unwrap();
+    if is_current(workdir) {
+        return
+    }
+    let tmpdir = format!("{}.tmp-{}", workdir, unsafe { libc::getpid() });
+    // Left by a run that died with the same pid
+    let _ = fs::remove_dir_all(&tmpdir);
+    fs::create_dir_all(&tmpdir).unwrap();
+    for (relpath, entry) in FILES.entries() {
+        let path = staging_path(&tmpdir, relpath);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        // A staged mount left by a run that died with the same pid
+        let _ = fs::remove_file(&path);
+        let mut f = File::create(&path).unwrap();
+        f.write_all(&store::contents(entry)).unwrap();
+        // Keeps executables executable. Read only files are no obstacle to
+        // later runs, which never write over an extracted file but replace
+        // whole trees and rename over mounts.
+        fs::set_permissions(&path, fs::Permissions::from_mode(entry.mode)).unwrap();
+        set_mtime(&path, entry.mtime);
+    }
+    for (relpath, entry) in LINKS.entries() {
+        let path = staging_path(&tmpdir, relpath);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+        // Relative targets come out right wherever the tree ends up
+        symlink(entry.target, path).unwrap();
+    }
+    // Renamed over whatever an earlier payload left there, so each is
+    // replaced whole or not at all. Done before the workdir appears, as
+    // that's what tells later runs everything is in place.
+    for relpath in FILES.keys().chain(LINKS.keys()).filter(|relpath| relpath.starts_with("/")) {
+        let path = key_path(relpath);
+        if let Err(err) = fs::rename(staging_path(&tmpdir, relpath), &path) {
+            panic!("can't write {}, extra mounts need write access: {}", path.display(), err)
         }
     }
+    File::create(Path::new(&tmpdir).join(MARKER)).unwrap().write_all(PAYLOAD_HASH.as_bytes()).unwrap();
+    // Directories last, as writing into them changes their mtimes. They
+    // keep owner rwx whatever they were built with so later runs can still
+    // replace the tree. Those under mounts may be shared with the host, so
+    // are left as they are.
+    for (relpath, entry) in DIRS.entries().filter(|&(relpath, _)| !relpath.starts_with("/")) {
+        let path = Path::new(&tmpdir).join(relpath);
+        fs::set_permissions(&path, fs::Permissions::from_mode(entry.mode | 0o700)).unwrap();
+        set_mtime(&path, entry.mtime);
+    }
+    let olddir = format!("{}.old-{}", workdir, unsafe { libc::getpid() });
+    if Path::new(workdir).exists() && !is_current(workdir) && fs::rename(workdir, &olddir).is_ok() {
+        fs::remove_dir_all(&olddir).unwrap();
+    }
+    if fs::rename(&tmpdir, workdir).is_err() {
+        // Lost the race to another run, use theirs
+        assert!(is_current(workdir));
+        fs::remove_dir_all(&tmpdir).unwrap();
+    }
 }
 
-pub fn atexit() {
-    let workdir = unsafe { WORKDIR };
-    fs::remove_dir_all(workdir).unwrap();
+extern {
+    fn utimes(filename: *const c_char, times: *const libc::timeval) -> c_int;
+}
+
+// Gives path the mtime (and atime) it was built with
+fn set_mtime(path: &Path, mtime: i64) {
+    let cpath = CString::new(path.to_str().unwrap()).unwrap();
+    let time = libc::timeval { tv_sec: mtime as libc::time_t, tv_usec: 0 };
+    let times = [time, time];
+    assert!(unsafe { utimes(cpath.as_ptr(), times.as_ptr()) } == 0, "can't set mtime of {}", path.display());
+}
+
+fn is_current(workdir: &str) -> bool {
+    let mut hash = String::new();
+    match File::open(Path::new(workdir).join(MARKER)) {
+        Ok(mut f) => f.read_to_string(&mut hash).is_ok() && hash == PAYLOAD_HASH,
+        Err(_) => false,
+    }
+}
+
+// Where an entry is written before being moved into place. Extra mounts
+// needn't be on the same filesystem as the workdir, so they're staged next
+// to their final paths instead.
+fn staging_path(tmpdir: &str, relpath: &str) -> PathBuf {
+    if relpath.starts_with("/") {
+        PathBuf::from(format!("{}.tmp-{}", relpath, unsafe { libc::getpid() }))
+    } else {
+        Path::new(tmpdir).join(relpath)
+    }
+}
+
+pub fn atexit() {}
+
+// The tree for the importer, read from disk as the application may have
+// changed it since extraction
+pub fn key_is_file(key: &str) -> bool {
+    fs::metadata(key_path(key)).map(|meta| meta.is_file()).unwrap_or(false)
+}
+pub fn key_is_dir(key: &str) -> bool {
+    fs::metadata(key_path(key)).map(|meta| meta.is_dir()).unwrap_or(false)
+}
+pub fn key_link_target(key: &str) -> Option<String> {
+    fs::read_link(key_path(key)).ok().and_then(|target| target.to_str().map(String::from))
+}
+pub fn key_contents(key: &str) -> Option<Contents<'static>> {
+    let mut data = vec![];
+    match File::open(key_path(key)).and_then(|mut f| f.read_to_end(&mut data)) {
+        Ok(_) => Some(Contents::Inflated(Arc::new(data))),
+        Err(_) => None,
+    }
 }
@@ -0,0 +1,343 @@
+// The _pyinrs builtin module, which puts a finder on sys.meta_path serving
+// modules and packages (source or compiled) straight from the embedded
+// tree, as the backend says it currently stands. Imports then don't depend
+// on CPython's file handling going through the backend. It also sets up
+// pyinrs.resources, for applications to get at their bundled data.
+
+use std::env;
+use std::mem;
+use std::ptr;
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+use libc;
+use libc::{c_char, c_int, c_long, ssize_t};
+use py::PyObject;
+
+use super::{FILES, DIRS, LINKS, resolve_key, key_path};
+use super::store;
+use super::backend;
+
+const PYTHON_API_VERSION: c_int = 1013;
+const PY_FILE_INPUT: c_int = 257;
+const METH_VARARGS: c_int = 1;
+
+type PyCFunction = unsafe extern fn(slf: *mut PyObject, args: *mut PyObject) -> *mut PyObject;
+
+#[repr(C)]
+struct PyMethodDef {
+    ml_name: *const c_char,
+    ml_meth: Option<PyCFunction>,
+    ml_flags: c_int,
+    ml_doc: *const c_char,
+}
+
+extern {
+    fn PyImport_AppendInittab(name: *const c_char, initfunc: extern fn()) -> c_int;
+    // Py_InitModule4 on 64 bit platforms
+    fn Py_InitModule4_64(name: *const c_char, methods: *mut PyMethodDef, doc: *const c_char,
+                         slf: *mut PyObject, apiver: c_int) -> *mut PyObject;
+    fn PyModule_GetDict(module: *mut PyObject) -> *mut PyObject;
+    fn PyArg_ParseTuple(args: *mut PyObject, format: *const c_char, ...) -> c_int;
+    fn Py_BuildValue(format: *const c_char, ...) -> *mut PyObject;
+    fn PyString_FromStringAndSize(v: *const c_char, len: ssize_t) -> *mut PyObject;
+    fn PyString_FromString(v: *const c_char) -> *mut PyObject;
+    fn PyList_New(len: ssize_t) -> *mut PyObject;
+    fn PyList_Append(list: *mut PyObject, item: *mut PyObject) -> c_int;
+    fn PyRun_StringFlags(s: *const c_char, start: c_int, globals: *mut PyObject, locals: *mut PyObject,
+                         flags: *mut c_char) -> *mut PyObject;
+    fn PyErr_Print();
+    fn Py_DecRef(o: *mut PyObject);
+    // Set by -O, which makes .pyo the compiled form to prefer
+    static Py_OptimizeFlag: c_int;
+}
+
+// The finder and loaders, run in the module's namespace. _find and _read
+// do the lookups.
+const FINDER: &'static str = "
+import sys
+import imp
+import marshal
+
+class Loader(object):
+    def __init__(self, fullname, filename, ispkg):
+        self.fullname = fullname
+        self.filename = filename
+        self.ispkg = ispkg
+    def load_module(self, fullname):
+        code = self.get_code(fullname)
+        mod = sys.modules.setdefault(fullname, imp.new_module(fullname))
+        mod.__file__ = self.filename
+        mod.__loader__ = self
+        if self.ispkg:
+            mod.__path__ = [self.filename.rpartition('/')[0]]
+            mod.__package__ = fullname
+        else:
+            mod.__package__ = fullname.rpartition('.')[0]
+        try:
+            exec code in mod.__dict__
+        except:
+            sys.modules.pop(fullname, None)
+            raise
+        return sys.modules[fullname]
+    def is_package(self, fullname):
+        return self.ispkg
+    def get_filename(self, fullname):
+        return self.filename
+    def is_compiled(self):
+        return self.filename.endswith(('.pyc', '.pyo'))
+    def get_source(self, fullname):
+        if self.is_compiled():
+            return None
+        return self.get_data(self.filename)
+    def get_code(self, fullname):
+        if self.is_compiled():
+            # A magic number then a timestamp, then the marshalled code
+            data = self.get_data(self.filename)
+            if data[:4] != imp.get_magic():
+                raise ImportError('bad magic number in %s' % self.filename)
+            return marshal.loads(data[8:])
+        source = self.get_source(fullname).replace('\\r\\n', '\\n')
+        return compile(source, self.filename, 'exec')
+    def get_data(self, path):
+        data = _read(path)
+        if data is None:
+            raise IOError(2, 'No such file or directory', path)
+        return data
+
+class Finder(object):
+    def find_module(self, fullname, path=None):
+        name = fullname.rpartition('.')[2]
+        for entry in (sys.path if path is None else path):
+            found = _find(entry, name)
+            if found is not None:
+                return Loader(fullname, found[0], found[1])
+        return None
+
+sys.meta_path.append(Finder())
+";
+
+// pyinrs.resources, run in its namespace over _list, _read and _stat
+const RESOURCES: &'static str = "
+'''Read only access to the files embedded in the program. Paths are relative
+to the directory the payload is served at, or absolute for extra mounts.'''
+import sys
+import errno
+import posix
+
+def _error(code, path):
+    return IOError(code, posix.strerror(code), path)
+
+def list(path=''):
+    names = _list(path)
+    if names is None:
+        raise _error(errno.ENOENT if _stat(path) is None else errno.ENOTDIR, path)
+    return names
+
+def read(path):
+    data = _read(path)
+    if data is None:
+        raise _error(errno.ENOENT if _stat(path) is None else errno.EISDIR, path)
+    return data
+
+def stat(path):
+    st = _stat(path)
+    if st is None:
+        raise _error(errno.ENOENT, path)
+    mode, size, mtime = st
+    return posix.stat_result((mode, 0, 0, 1, posix.getuid(), posix.getgid(), size, mtime, mtime, mtime))
+
+def exists(path):
+    return _stat(path) is not None
+
+def open(path):
+    import io
+    return io.BytesIO(read(path))
+
+_pkg = sys.modules.setdefault('pyinrs', type(sys)('pyinrs'))
+_pkg.__path__ = []
+_pkg.resources = sys.modules[__name__]
+";
+
+// Makes _pyinrs importable, must be called before Py_Initialize
+pub fn register() {
+    unsafe { PyImport_AppendInittab(b"_pyinrs\0".as_ptr() as *const c_char, init_pyinrs) };
+}
+
+extern fn init_pyinrs() {
+    unsafe {
+        init_module(b"_pyinrs\0", vec![
+            method(b"_find\0", find, b"_find(entry, name) -> (filename, ispkg) or None\0"),
+            method(b"_read\0", read, b"_read(path) -> contents or None\0"),
+        ], FINDER);
+        init_module(b"pyinrs.resources\0", vec![
+            method(b"_list\0", resource_list, b"_list(path) -> names or None\0"),
+            method(b"_read\0", resource_read, b"_read(path) -> contents or None\0"),
+            method(b"_stat\0", resource_stat, b"_stat(path) -> (mode, size, mtime) or None\0"),
+        ], RESOURCES);
+    }
+}
+
+fn method(name: &'static [u8], meth: PyCFunction, doc: &'static [u8]) -> PyMethodDef {
+    PyMethodDef {
+        ml_name: name.as_ptr() as *const c_char,
+        ml_meth: Some(meth),
+        ml_flags: METH_VARARGS,
+        ml_doc: doc.as_ptr() as *const c_char,
+    }
+}
+
+// Creates a module with methods, then runs source in its namespace
+unsafe fn init_module(name: &'static [u8], mut methods: Vec<PyMethodDef>, source: &str) {
+    methods.push(PyMethodDef { ml_name: ptr::null(), ml_meth: None, ml_flags: 0, ml_doc: ptr::null() });
+    // Python keeps the method table for good
+    let methods_ptr = methods.as_mut_ptr();
+    mem::forget(methods);
+    let module = Py_InitModule4_64(name.as_ptr() as *const c_char, methods_ptr,
+                                   ptr::null(), ptr::null_mut(), PYTHON_API_VERSION);
+    if module.is_null() {
+        return
+    }
+    let dict = PyModule_GetDict(module);
+    let source = CString::new(source).unwrap();
+    let ret = PyRun_StringFlags(source.as_ptr(), PY_FILE_INPUT, dict, dict, ptr::null_mut());
+    if ret.is_null() {
+        PyErr_Print()
+    } else {
+        Py_DecRef(ret)
+    }
+}
+
+// The absolute path for a path as Python has it, relative paths being
+// relative to the current directory
+fn abs_path_for(path: &str) -> Option<PathBuf> {
+    env::current_dir().ok().map(|cwd| cwd.join(path))
+}
+// The key for a path as Python has it, following links as the backend has
+// them
+fn key_for(path: &Path) -> Option<String> {
+    resolve_key(path, true, backend::key_link_target).ok()
+}
+// Resource paths are relative to the workdir rather than the current
+// directory
+fn resource_key(path: &str) -> Option<String> {
+    resolve_key(&key_path(path), true, link_target).ok()
+}
+fn link_target(key: &str) -> Option<String> {
+    LINKS.get(key).map(|link| String::from(link.target))
+}
+
+unsafe fn none() -> *mut PyObject {
+    Py_BuildValue(b"\0".as_ptr() as *const c_char)
+}
+
+unsafe extern fn find(_slf: *mut PyObject, args: *mut PyObject) -> *mut PyObject {
+    let mut entry: *const c_char = ptr::null();
+    let mut name: *const c_char = ptr::null();
+    if PyArg_ParseTuple(args, b"ss\0".as_ptr() as *const c_char, &mut entry, &mut name) == 0 {
+        return ptr::null_mut()
+    }
+    let (entry, name) = match (CStr::from_ptr(entry).to_str(), CStr::from_ptr(name).to_str()) {
+        (Ok(entry), Ok(name)) => (entry, name),
+        _ => return none(),
+    };
+    let dir = match abs_path_for(entry) {
+        Some(dir) => dir,
+        None => return none(),
+    };
+    match key_for(&dir) {
+        Some(ref key) if backend::key_is_dir(key) => (),
+        _ => return none(),
+    }
+    let suffixes = if Py_OptimizeFlag != 0 { [".py", ".pyo", ".pyc"] } else { [".py", ".pyc", ".pyo"] };
+    // Packages win over modules, as for the path based import. Source wins
+    // over compiled, which isn't checked for being up to date.
+    let pkgdir = dir.join(name);
+    let candidates = suffixes.iter().map(|suffix| (pkgdir.join(&format!("__init__{}", suffix)), true))
+        .chain(suffixes.iter().map(|suffix| (dir.join(&format!("{}{}", name, suffix)), false)));
+    for (path, ispkg) in candidates {
+        // The filename keeps any links in it, as with the path based import
+        if !key_for(&path).map_or(false, |key| backend::key_is_file(&key)) {
+            continue
+        }
+        let filename = CString::new(path.to_str().unwrap()).unwrap();
+        return Py_BuildValue(b"(si)\0".as_ptr() as *const c_char, filename.as_ptr(), ispkg as c_int)
+    }
+    none()
+}
+
+unsafe extern fn read(_slf: *mut PyObject, args: *mut PyObject) -> *mut PyObject {
+    let mut path: *const c_char = ptr::null();
+    if PyArg_ParseTuple(args, b"s\0".as_ptr() as *const c_char, &mut path) == 0 {
+        return ptr::null_mut()
+    }
+    let key = CStr::from_ptr(path).to_str().ok().and_then(abs_path_for).and_then(|path| key_for(&path));
+    let data = match key.and_then(|key| backend::key_contents(&key)) {
+        Some(data) => data,
+        None => return none(),
+    };
+    PyString_FromStringAndSize(data.as_ptr() as *const c_char, data.len() as ssize_t)
+}
+
+unsafe fn parse_path<'a>(args: *mut PyObject) -> Option<&'a str> {
+    let mut path: *const c_char = ptr::null();
+    if PyArg_ParseTuple(args, b"s\0".as_ptr() as *const c_char, &mut path) == 0 {
+        return None
+    }
+    // Embedded paths are all utf-8
+    Some(CStr::from_ptr(path).to_str().unwrap_or("/\u{fffd}"))
+}
+
+unsafe extern fn resource_list(_slf: *mut PyObject, args: *mut PyObject) -> *mut PyObject {
+    let path = match parse_path(args) {
+        Some(path) => path,
+        None => return ptr::null_mut(),
+    };
+    let dir = match resource_key(path).and_then(|key| DIRS.get(&key[..])) {
+        Some(dir) => dir,
+        None => return none(),
+    };
+    let list = PyList_New(0);
+    for &(child, _) in dir.children {
+        let name = CString::new(Path::new(child).file_name().unwrap().to_str().unwrap()).unwrap();
+        let item = PyString_FromString(name.as_ptr());
+        let ret = PyList_Append(list, item);
+        Py_DecRef(item);
+        if ret < 0 {
+            Py_DecRef(list);
+            return ptr::null_mut()
+        }
+    }
+    list
+}
+
+unsafe extern fn resource_read(_slf: *mut PyObject, args: *mut PyObject) -> *mut PyObject {
+    let path = match parse_path(args) {
+        Some(path) => path,
+        None => return ptr::null_mut(),
+    };
+    let entry = match resource_key(path).and_then(|key| FILES.get(&key[..])) {
+        Some(entry) => entry,
+        None => return none(),
+    };
+    let data = store::contents(entry);
+    PyString_FromStringAndSize(data.as_ptr() as *const c_char, data.len() as ssize_t)
+}
+
+unsafe extern fn resource_stat(_slf: *mut PyObject, args: *mut PyObject) -> *mut PyObject {
+    let path = match parse_path(args) {
+        Some(path) => path,
+        None => return ptr::null_mut(),
+    };
+    let key = match resource_key(path) {
+        Some(key) => key,
+        None => return none(),
+    };
+    let (mode, size, mtime) = if let Some(entry) = FILES.get(&key[..]) {
+        (libc::S_IFREG | entry.mode, entry.size, entry.mtime)
+    } else if let Some(entry) = DIRS.get(&key[..]) {
+        (libc::S_IFDIR | entry.mode, 1024, entry.mtime)
+    } else {
+        return none()
+    };
+    Py_BuildValue(b"(lll)\0".as_ptr() as *const c_char, mode as c_long, size as c_long, mtime as c_long)
+}
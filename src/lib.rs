@@ -5,20 +5,188 @@
 
 extern crate libc;
 extern crate phf;
+extern crate python27_sys as py;
 #[macro_use]
 extern crate lazy_static;
 
 use std::rt;
+use std::env;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf, Component};
+use libc::c_int;
 
-// Defines static FILES: phf::Map<&'static str, &'static [u8]>
-//         static DIRS:  phf::Set<&'static str>
+// An embedded file as build.rs stored it, zlib compressed unless opted out.
+// size is always the uncompressed size. Read it through store::contents.
+// mtime and mode (permission bits only) come from the source file.
+pub struct FileEntry {
+    data: &'static [u8],
+    size: usize,
+    compressed: bool,
+    mtime: i64,
+    mode: u32,
+}
+
+// children are the keys of the directory's entries and whether they're
+// directories (symlinks count as not)
+pub struct DirEntry {
+    children: &'static [(&'static str, bool)],
+    mtime: i64,
+    mode: u32,
+}
+
+// A symlink, target as it was written (relative to the link's directory
+// unless absolute)
+pub struct LinkEntry {
+    target: &'static str,
+    mtime: i64,
+}
+
+// Defines static ROOT: &'static str
+//         static PAYLOAD_HASH: &'static str
+//         static MOUNTS: &'static [&'static str]
+//         static FILES: phf::Map<&'static str, FileEntry>
+//         static DIRS:  phf::Map<&'static str, DirEntry>
+//         static LINKS: phf::Map<&'static str, LinkEntry>
+//         static MANIFEST: &'static str
+// FILES, DIRS and LINKS are keyed by path relative to WORKDIR, or by absolute path
+// for files under one of the extra MOUNTS (see path_key).
 include!("../include.files");
 
-#[cfg(any(feature = "dump", feature = "wrap"))]
+#[cfg(any(feature = "dump", feature = "wrap", feature = "memfd"))]
 const VALIDMODE: bool = true;
 
 static mut WORKDIR: &'static str = "";
 
+// The workdir for unit tests. Nothing synchronises WORKDIR, so it's set
+// just once, before any test can read it.
+#[cfg(test)]
+fn test_workdir() -> &'static str {
+    use std::sync::{Once, ONCE_INIT};
+    static SET: Once = ONCE_INIT;
+    SET.call_once(|| unsafe { WORKDIR = "/pyinrs-test" });
+    unsafe { WORKDIR }
+}
+
+// Where everything in include/ is served, PYINRS_ROOT at runtime or the root
+// chosen at build time (by default derived from a hash of the payload, so
+// it's stable between runs). {hash} in PYINRS_ROOT stands for the payload
+// hash, as it does at build time. The wrap and memfd backends never touch
+// the real directory. An unusable PYINRS_ROOT is ignored with a warning.
+pub fn root() -> String {
+    let root = match env::var("PYINRS_ROOT") {
+        Ok(ref root) if root != "" => valid_root(&root.replace("{hash}", PAYLOAD_HASH)),
+        Ok(_) | Err(env::VarError::NotPresent) => return String::from(ROOT),
+        Err(env::VarError::NotUnicode(_)) => Err("it isn't utf-8"),
+    };
+    root.unwrap_or_else(|why| {
+        let _ = writeln!(&mut io::stderr(), "pyinrs: ignoring PYINRS_ROOT, {}", why);
+        String::from(ROOT)
+    })
+}
+
+// root without trailing slashes, as build.rs checks it. Keys are made by
+// comparing paths with it component by component, so it has to be
+// absolute and normalised, and / itself would put everything in the tree.
+fn valid_root(root: &str) -> Result<String, &'static str> {
+    if !root.starts_with("/") {
+        return Err("it must be absolute")
+    }
+    let root = root.trim_right_matches('/');
+    if root == "" {
+        return Err("it can't be /")
+    }
+    if root[1..].split('/').any(|part| part == "" || part == "." || part == "..") {
+        return Err("it must be normalised, without . or .. or repeated slashes")
+    }
+    Ok(String::from(root))
+}
+
+// The key into FILES and DIRS for an absolute path, whether or not anything
+// exists there. None if it's outside every mount.
+fn path_key(path: &Path) -> Option<String> {
+    let workdir = unsafe { WORKDIR };
+    if let Some(relpath) = path.relative_from(workdir) {
+        return Some(String::from(relpath.to_str().unwrap()))
+    }
+    // Rebuilt from the components so it's normalised like relpaths are
+    let path = match path.relative_from("/") {
+        Some(path) => Path::new("/").join(path),
+        None => return None,
+    };
+    if MOUNTS.iter().any(|mount| path.starts_with(mount)) {
+        return Some(String::from(path.to_str().unwrap()))
+    }
+    None
+}
+
+// As Linux's limit on links followed during one lookup
+const MAXSYMLINKS: usize = 40;
+
+// The key for an absolute path after following the links in it that are in
+// the tree (the last component's only if follow), link_target giving the
+// target of the link at a key if there is one. ENOENT if the path is or
+// leads outside every mount.
+fn resolve_key<F>(path: &Path, follow: bool, link_target: F) -> Result<String, c_int>
+        where F: Fn(&str) -> Option<String> {
+    // Components left to resolve, last first
+    let mut pending: Vec<String> = vec![];
+    push_components(&mut pending, path);
+    let mut resolved = PathBuf::from("/");
+    let mut nlinks = 0;
+    while let Some(name) = pending.pop() {
+        // resolved never contains links, so .. can be taken literally
+        if name == ".." {
+            resolved.pop();
+            continue
+        }
+        resolved.push(&name);
+        if pending.is_empty() && !follow {
+            break
+        }
+        let target = match path_key(&resolved).and_then(|key| link_target(&key)) {
+            Some(target) => target,
+            None => continue,
+        };
+        nlinks += 1;
+        if nlinks > MAXSYMLINKS {
+            return Err(libc::ELOOP)
+        }
+        resolved.pop();
+        // An absolute target replaces everything resolved so far
+        push_components(&mut pending, &resolved.join(&target));
+        resolved = PathBuf::from("/");
+    }
+    path_key(&resolved).ok_or(libc::ENOENT)
+}
+
+// Queues path's components to be resolved ahead of those already pending
+fn push_components(pending: &mut Vec<String>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(name) => pending.push(String::from(name.to_str().unwrap())),
+            Component::ParentDir => pending.push(String::from("..")),
+            _ => (),
+        }
+    }
+}
+
+// The absolute path for a key, the inverse of path_key
+fn key_path(key: &str) -> PathBuf {
+    let workdir = unsafe { WORKDIR };
+    // Joining an absolute key replaces the workdir
+    Path::new(workdir).join(key)
+}
+
+// The key of the root of the mount a key is under, "" for the workdir
+fn mount_root(key: &str) -> &'static str {
+    MOUNTS.iter().find(|mount| Path::new(key).starts_with(mount)).map(|mount| *mount).unwrap_or("")
+}
+// Mount roots can't be removed or renamed
+fn is_mount_root(key: &str) -> bool {
+    mount_root(key) == key
+}
+
 pub fn prep(workdir: &'static str) {
     assert!(VALIDMODE);
     unsafe { WORKDIR = workdir };
@@ -27,6 +195,15 @@ pub fn prep(workdir: &'static str) {
     rt::at_exit(backend::atexit).unwrap();
 }
 
+#[allow(dead_code)]
+mod sys;
+mod store;
+pub mod importer;
+pub mod manifest;
+
+#[cfg(feature = "wrap")]
+mod trace;
+
 #[cfg(feature = "wrap")]
 #[path = "wrap.rs"]
 pub mod backend;
@@ -34,3 +211,98 @@ pub mod backend;
 #[cfg(feature = "dump")]
 #[path = "dump.rs"]
 pub mod backend;
+
+#[cfg(feature = "memfd")]
+#[path = "memfd.rs"]
+pub mod backend;
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use libc;
+    use super::{FILES, DIRS, LINKS, test_workdir, resolve_key, valid_root, is_mount_root};
+
+    // Keys under the workdir, with links as given
+    fn resolve(path: &str, follow: bool, links: &[(&str, &str)]) -> Result<String, i32> {
+        let path = Path::new(test_workdir()).join(path);
+        resolve_key(&path, follow, |key| {
+            links.iter().find(|&&(link, _)| link == key).map(|&(_, target)| String::from(target))
+        })
+    }
+
+    #[test]
+    fn resolve_plain() {
+        assert_eq!(resolve("a/b", true, &[]), Ok(String::from("a/b")));
+        assert_eq!(resolve("a/./b/../c", true, &[]), Ok(String::from("a/c")));
+    }
+
+    #[test]
+    fn resolve_follows_links() {
+        let links = [("a", "x/y"), ("x/y/l", "../z")];
+        assert_eq!(resolve("a/f", true, &links), Ok(String::from("x/y/f")));
+        assert_eq!(resolve("a/l", true, &links), Ok(String::from("x/z")));
+        // Only the last component is left alone
+        assert_eq!(resolve("a/l", false, &links), Ok(String::from("x/y/l")));
+        assert_eq!(resolve("a", false, &links), Ok(String::from("a")));
+    }
+
+    #[test]
+    fn resolve_dotdot_across_link() {
+        // .. goes up from where the link leads, not from the link
+        let links = [("d", "x/y")];
+        assert_eq!(resolve("d/../f", true, &links), Ok(String::from("x/f")));
+        assert_eq!(resolve("d/../../../pyinrs-test/g", true, &links), Ok(String::from("g")));
+    }
+
+    #[test]
+    fn resolve_link_loops() {
+        assert_eq!(resolve("a", true, &[("a", "a")]), Err(libc::ELOOP));
+        assert_eq!(resolve("a/f", true, &[("a", "b"), ("b", "a")]), Err(libc::ELOOP));
+        // Not following the last component means not going round
+        assert_eq!(resolve("a", false, &[("a", "a")]), Ok(String::from("a")));
+    }
+
+    #[test]
+    fn resolve_dangling_target() {
+        // A key for where the link points whether or not anything's there
+        assert_eq!(resolve("l", true, &[("l", "missing/file")]), Ok(String::from("missing/file")));
+        // Outside every mount, so not in the tree at all
+        assert_eq!(resolve("l", true, &[("l", "/pyinrs-nonexistent/file")]), Err(libc::ENOENT));
+        assert_eq!(resolve("l", true, &[("l", "../..")]), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn dirs_list_every_entry() {
+        let keys = FILES.keys().map(|key| (*key, false))
+            .chain(LINKS.keys().map(|key| (*key, false)))
+            .chain(DIRS.keys().map(|key| (*key, true)));
+        for (key, isdir) in keys.filter(|&(key, _)| !is_mount_root(key)) {
+            let parent = Path::new(key).parent().unwrap().to_str().unwrap();
+            let entry = DIRS.get(parent).expect(&format!("no directory for {}", key));
+            assert!(entry.children.contains(&(key, isdir)), "{} isn't listed in {:?}", key, parent);
+        }
+    }
+
+    #[test]
+    fn dir_children_exist() {
+        for (dir, entry) in DIRS.entries() {
+            for &(child, isdir) in entry.children {
+                assert_eq!(Path::new(child).parent(), Some(Path::new(dir)));
+                assert_eq!(isdir, DIRS.contains_key(child));
+                assert!(isdir || FILES.contains_key(child) || LINKS.contains_key(child), "{} doesn't exist", child);
+            }
+        }
+    }
+
+    #[test]
+    fn root_must_be_absolute_and_normal() {
+        assert_eq!(valid_root("/tmp/app"), Ok(String::from("/tmp/app")));
+        assert_eq!(valid_root("/tmp/app//"), Ok(String::from("/tmp/app")));
+        assert!(valid_root("tmp/app").is_err());
+        assert!(valid_root("/").is_err());
+        assert!(valid_root("//").is_err());
+        assert!(valid_root("/tmp//app").is_err());
+        assert!(valid_root("/tmp/./app").is_err());
+        assert!(valid_root("/tmp/../app").is_err());
+    }
+}
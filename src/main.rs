@@ -1,6 +1,5 @@
 extern crate pyinrs;
 extern crate python27_sys as py;
-extern crate uuid;
 extern crate libc;
 #[macro_use]
 extern crate lazy_static;
@@ -11,45 +10,52 @@ extern {}
 use std::ffi::CString;
 use std::ptr;
 use std::env;
+use std::process;
+use std::path::Path;
 use libc::{c_char, c_int};
 
 pub const PYTHONLIBNAME: &'static str = "libpython2.7.zip";
 lazy_static!{
-    pub static ref WORKDIR: String =
-        format!("/tmp/pyinrs-{}", uuid::Uuid::new_v4().to_simple_string());
+    pub static ref WORKDIR: String = pyinrs::root();
 }
 
 extern {
     fn PySys_SetArgvEx(argc: c_int, argv: *mut *mut c_char, updatepath: c_int);
+    fn Py_Main(argc: c_int, argv: *mut *mut c_char) -> c_int;
+}
+
+// Whether to behave like the python command rather than run the
+// application, for poking around the shipped environment. Chosen by
+// PYINRS_PYTHON, by being run as python* (e.g. through a symlink), or by
+// --pyinrs-python as the first argument, which is then dropped.
+fn python_mode(args: &mut Vec<String>) -> bool {
+    if args.len() > 1 && args[1] == "--pyinrs-python" {
+        args.remove(1);
+        return true
+    }
+    match env::var("PYINRS_PYTHON") {
+        Ok(ref val) if val != "" && val != "0" => return true,
+        _ => (),
+    }
+    match args.first().and_then(|arg0| Path::new(arg0).file_name()).and_then(|name| name.to_str()) {
+        Some(name) => name.starts_with("python"),
+        None => false,
+    }
 }
 
 fn main() {
     pyinrs::prep(&*WORKDIR);
     env::set_var("PYTHONPATH", format!("{}/{}", &*WORKDIR, PYTHONLIBNAME));
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let python = python_mode(&mut args);
 
     let pyhome_str = "";
     let pyhome_cstr = CString::new(pyhome_str.as_bytes()).unwrap();
-    let cmd_str = format!("
-import sys
-workdir = '{}'
-#import socket
-#import _socket
-#print socket.gethostname()
-#print _socket.gethostname()
-#import wave
-#import base64
-#sys.exit(1)
-sys.path.insert(0, workdir + '/dep')
-sys.path.insert(0, workdir) # shutit uses sys.path[0] as file location
-import shutit_util
-shutit_util._default_cnf = shutit_util._default_cnf.replace(
-    'shutit_module_path:',
-    'shutit_module_path:' + workdir + '/library:'
-) # such a hack
-import shutit_main
-shutit_main.main()
-", *WORKDIR);
+    let cmd_str = if python {
+        pyinrs::manifest::environment(&*WORKDIR)
+    } else {
+        pyinrs::manifest::bootstrap(&*WORKDIR)
+    };
     let cmd_cstr = CString::new(cmd_str.as_bytes()).unwrap();
 
     let mut cstr_args: Vec<CString> = vec![];
@@ -66,7 +72,17 @@ shutit_main.main()
         py::Py_NoUserSiteDirectory = 1;
         py::Py_DontWriteBytecodeFlag = 1;
         py::Py_SetPythonHome(pyhome_cstr.as_ptr() as *mut i8);
+        pyinrs::importer::register();
         py::Py_Initialize();
+        if python {
+            // Py_Main's own initialisation is then a no-op, so whatever it
+            // runs (-c, -m, a script or the REPL) sees the same sys.path and
+            // hooks as the application would. Flags it would apply before
+            // initialising, like -E and -S, have no effect.
+            py::PyRun_SimpleStringFlags(cmd_cstr.as_ptr(), ptr::null_mut());
+            let ret = Py_Main(ptr_args.len() as c_int, ptr_args.as_ptr() as *mut *mut c_char);
+            process::exit(ret)
+        }
         PySys_SetArgvEx(ptr_args.len() as c_int, ptr_args.as_ptr() as *mut *mut c_char, 0);
         //let mut flags = py::PyCompilerFlags { cf_flags: 0 };
         let flags = ptr::null_mut();
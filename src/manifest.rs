@@ -0,0 +1,205 @@
+// Turns MANIFEST (see shutit.manifest, checked by build.rs) into the Python
+// that starts the application
+
+use super::MANIFEST;
+
+fn entries() -> Vec<(&'static str, &'static str)> {
+    parse(MANIFEST)
+}
+
+// key, value
+fn parse(manifest: &'static str) -> Vec<(&'static str, &'static str)> {
+    manifest.lines()
+        .map(|line| line.trim_right())
+        .filter(|line| *line != "" && !line.trim_left().starts_with("#"))
+        .map(|line| {
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = parts.next().unwrap();
+            // Only the space after = goes from code, so its lines keep their
+            // indent. Everything else is trimmed, as build.rs checked it.
+            match key {
+                "code" => (key, if value.starts_with(" ") { &value[1..] } else { value }),
+                _ => (key, value.trim()),
+            }
+        })
+        .collect()
+}
+
+// A Python 2 str literal with s's utf-8 bytes, escaped so the source stays
+// ascii
+fn py_str(s: &str) -> String {
+    let mut lit = String::from("'");
+    for &b in s.as_bytes() {
+        match b {
+            b'\\' => lit.push_str("\\\\"),
+            b'\'' => lit.push_str("\\'"),
+            0x20...0x7e => lit.push(b as char),
+            _ => lit.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    lit.push('\'');
+    lit
+}
+
+// A Python expression for a payload path
+fn path_expr(path: &str) -> String {
+    if path == "." {
+        String::from("workdir")
+    } else if path.starts_with("/") {
+        py_str(path)
+    } else {
+        format!("workdir + {}", py_str(&format!("/{}", path.trim_right_matches('/'))))
+    }
+}
+
+// Sets up sys.path and runs the hooks, with workdir bound to the payload
+// root
+pub fn environment(workdir: &str) -> String {
+    let mut script = environment_lines(workdir, &entries());
+    script.push(String::new());
+    script.join("\n")
+}
+
+// As environment, then runs the application
+pub fn bootstrap(workdir: &str) -> String {
+    let entries = entries();
+    let mut script = environment_lines(workdir, &entries);
+    for &(key, value) in entries.iter() {
+        match key {
+            "entry" => {
+                let mut parts = value.splitn(2, ':');
+                let module = parts.next().unwrap().trim();
+                let function = parts.next().unwrap().trim();
+                script.push(format!("import {}", module));
+                script.push(format!("sys.exit({}.{}())", module, function));
+            },
+            "script" => {
+                // Read from the payload directly, so it works whatever the
+                // backend
+                script.push(format!("__file__ = sys.argv[0] = {}", path_expr(value)));
+                script.push(String::from("_source = _pyinrs._read(__file__)"));
+                script.push(String::from("if _source is None:"));
+                script.push(String::from("    sys.exit('pyinrs: can\\'t read script %s' % __file__)"));
+                script.push(String::from("exec compile(_source, __file__, 'exec')"));
+            },
+            "code" => script.push(String::from(value)),
+            _ => (),
+        }
+    }
+    script.push(String::new());
+    script.join("\n")
+}
+
+fn environment_lines(workdir: &str, entries: &[(&'static str, &'static str)]) -> Vec<String> {
+    let mut script = vec![
+        String::from("import sys"),
+        String::from("import _pyinrs # serves imports from the embedded files"),
+        format!("workdir = {}", py_str(workdir)),
+    ];
+    let paths: Vec<String> = entries.iter()
+        .filter(|&&(key, _)| key == "path")
+        .map(|&(_, path)| path_expr(path))
+        .collect();
+    script.push(format!("sys.path[0:0] = [{}]", paths.join(", ")));
+    for &(key, value) in entries.iter() {
+        if key == "hook" {
+            script.push(String::from(value))
+        }
+    }
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, py_str, path_expr, environment_lines};
+
+    // build.rs's checks, with bin/run.py the only file in the payload
+    mod build {
+        include!("check_manifest.rs");
+
+        pub fn check(manifest: &str) {
+            check_manifest("test.manifest", manifest, |key| key == "bin/run.py")
+        }
+    }
+    use self::build::check;
+
+    #[test]
+    fn parse_skips_comments_and_blanks() {
+        let manifest = "# comment\n\n  # indented comment\npath = lib \nentry=app:main\n";
+        assert_eq!(parse(manifest), vec![("path", "lib"), ("entry", "app:main")]);
+    }
+
+    #[test]
+    fn parse_keeps_code_indent() {
+        let manifest = "code = if True:\ncode =     pass\ncode=x = 1\n";
+        assert_eq!(parse(manifest), vec![("code", "if True:"), ("code", "    pass"), ("code", "x = 1")]);
+    }
+
+    #[test]
+    fn str_literals_stay_ascii() {
+        assert_eq!(py_str("plain"), "'plain'");
+        assert_eq!(py_str("it's a \\"), "'it\\'s a \\\\'");
+        assert_eq!(py_str("\n\u{e9}"), "'\\x0a\\xc3\\xa9'");
+    }
+
+    #[test]
+    fn paths_are_relative_to_workdir() {
+        assert_eq!(path_expr("."), "workdir");
+        assert_eq!(path_expr("dep"), "workdir + '/dep'");
+        assert_eq!(path_expr("dep/"), "workdir + '/dep'");
+        assert_eq!(path_expr("/opt/app"), "'/opt/app'");
+    }
+
+    #[test]
+    fn environment_sets_path_then_hooks() {
+        let lines = environment_lines("/w", &parse("path = .\nhook = import x\npath = dep\nentry = a:b\n"));
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[2], "workdir = '/w'");
+        assert_eq!(lines[3], "sys.path[0:0] = [workdir, workdir + '/dep']");
+        assert_eq!(lines[4], "import x");
+    }
+
+    #[test]
+    fn check_accepts_one_way_to_run() {
+        check("path = .\nentry = app:main\n");
+        check("script = bin/run.py\n");
+        check("code = import app\ncode = app.main()\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "needs one entry, one script or some code")]
+    fn check_rejects_nothing_to_run() {
+        check("path = .\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "needs one entry, one script or some code")]
+    fn check_rejects_two_ways_to_run() {
+        check("entry = app:main\ncode = pass\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "entry must be module:function")]
+    fn check_rejects_bad_entry() {
+        check("entry = app\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't in the payload")]
+    fn check_rejects_missing_script() {
+        check("script = bin/missing.py\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown key")]
+    fn check_rejects_unknown_keys() {
+        check("entry = app:main\nmodule = app\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "expected key = value")]
+    fn check_rejects_lines_without_equals() {
+        check("entry app:main\n");
+    }
+}
@@ -0,0 +1,599 @@
+#![allow(non_snake_case)]
+
+use std::ffi::{CStr, CString};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::env;
+use std::mem;
+use std::ptr;
+use std::str;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use libc;
+use libc::{c_int, c_long, c_char, size_t, ssize_t};
+
+use super::{FILES, DIRS, LINKS, resolve_key, key_path, is_mount_root};
+use super::store;
+use super::store::Contents;
+use super::sys;
+use super::sys::{dirent, set_errno, get_errno, AT_FDCWD, O_ACCMODE, O_DIRECTORY};
+use super::sys::{DT_DIR, DT_REG, DT_LNK, R_OK, W_OK, X_OK};
+
+static mut IS_INITIALISED: bool = false;
+
+pub fn prep() {
+    // Make sure MS is initialised
+    assert!(MS().dirps.is_empty());
+    unsafe { IS_INITIALISED = true };
+}
+
+pub fn atexit() {}
+
+// The tree for the importer, which nothing changes in this backend
+pub fn key_is_file(key: &str) -> bool {
+    FILES.contains_key(key)
+}
+pub fn key_is_dir(key: &str) -> bool {
+    DIRS.contains_key(key)
+}
+pub fn key_link_target(key: &str) -> Option<String> {
+    LINKS.get(key).map(|link| String::from(link.target))
+}
+pub fn key_contents(key: &str) -> Option<Contents<'static>> {
+    FILES.get(key).map(store::contents)
+}
+
+// Embedded files are materialised into a sealed memfd the first time they're
+// opened, and each open after that reopens the memfd via /proc/self/fd so it
+// gets its own offset. From then on the kernel does everything (read, mmap,
+// dup, fork, exec...), so only the calls that look up paths are wrapped,
+// along with fstat, which would otherwise show the memfd's own metadata.
+// Directories can't live in a memfd, so they are placeholder fds on a
+// removed directory with listings served by the readdir wrappers.
+
+struct MemfdState {
+    // relpath -> memfd holding the contents
+    memfds: HashMap<&'static str, c_int>,
+    // device, inode -> relpath, so fstat knows fds reopened from memfds
+    // however they were since duplicated
+    memfd_ids: HashMap<(libc::dev_t, libc::ino_t), &'static str>,
+    // fd -> relpath
+    dirfds: HashMap<c_int, &'static str>,
+    // dirp -> fd, entries (name, relpath, isdir), next_offset, cur_dirent
+    dirps: HashMap<usize, (c_int, Vec<(&'static str, &'static str, bool)>, usize, Box<dirent>)>,
+    // for stat calls
+    inodes: HashMap<&'static str, libc::ino_t>,
+}
+
+impl MemfdState {
+    // The file, directory or (unless follow) link fpath names
+    fn to_relpath(&self, dirfd: c_int, fpath: &str, follow: bool) -> Option<&'static str> {
+        let path = Path::new(fpath);
+        let abs_path = if path.is_absolute() {
+            PathBuf::from(path)
+        } else if dirfd == AT_FDCWD {
+            // getcwd isn't wrapped in this backend, so no deadlock. If the
+            // cwd is gone or isn't utf-8 nothing relative to it is embedded.
+            match env::current_dir().ok().and_then(|cwd| cwd.to_str().map(PathBuf::from)) {
+                Some(cwd) => cwd.join(path),
+                None => return None,
+            }
+        } else {
+            match self.dirfds.get(&dirfd) {
+                Some(relpath) => key_path(relpath).join(path),
+                None => return None,
+            }
+        };
+        let relpath = match resolve_key(&abs_path, follow, |key| LINKS.get(key).map(|l| String::from(l.target))) {
+            Ok(relpath) => relpath,
+            Err(_) => return None,
+        };
+        FILES.get_key(&relpath[..])
+            .or_else(|| DIRS.get_key(&relpath[..]))
+            .or_else(|| LINKS.get_key(&relpath[..]))
+            .map(|p| *p)
+    }
+
+    fn open(&mut self, relpath: &'static str, flags: c_int) -> Result<c_int, c_int> {
+        if DIRS.contains_key(relpath) {
+            if flags & O_ACCMODE != libc::O_RDONLY {
+                return Err(libc::EISDIR)
+            }
+            let fd = try!(sys::dir_placeholder(flags));
+            self.dirfds.insert(fd, relpath);
+            return Ok(fd)
+        }
+        if flags & O_DIRECTORY != 0 {
+            return Err(libc::ENOTDIR)
+        }
+        if flags & (libc::O_CREAT | libc::O_EXCL) == libc::O_CREAT | libc::O_EXCL {
+            return Err(libc::EEXIST)
+        }
+        if flags & O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            return Err(libc::EROFS)
+        }
+        let memfd = try!(self.get_memfd(relpath));
+        let procpath = CString::new(format!("/proc/self/fd/{}", memfd)).unwrap();
+        let fd = unsafe { __real_open(procpath.as_ptr(), flags & !libc::O_CREAT, 0) };
+        if fd < 0 { Err(get_errno()) } else { Ok(fd) }
+    }
+    fn get_memfd(&mut self, relpath: &'static str) -> Result<c_int, c_int> {
+        match self.memfds.get(relpath) {
+            Some(&memfd) => return Ok(memfd),
+            None => (),
+        }
+        let data = store::contents(FILES.get(relpath).unwrap());
+        let memfd = try!(sys::memfd_from_data(relpath, &data, sys::MFD_CLOEXEC));
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if sys::fstat(memfd, &mut stat) < 0 {
+            let errno = get_errno();
+            sys::close(memfd);
+            return Err(errno)
+        }
+        self.memfd_ids.insert((stat.st_dev, stat.st_ino), relpath);
+        self.memfds.insert(relpath, memfd);
+        Ok(memfd)
+    }
+    fn close(&mut self, fd: c_int) {
+        self.dirfds.remove(&fd);
+    }
+    // What's open on fd, if it's embedded
+    fn fd_relpath(&self, fd: c_int) -> Option<&'static str> {
+        if let Some(&relpath) = self.dirfds.get(&fd) {
+            return Some(relpath)
+        }
+        if self.memfd_ids.is_empty() {
+            return None
+        }
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if sys::fstat(fd, &mut stat) < 0 {
+            return None
+        }
+        self.memfd_ids.get(&(stat.st_dev, stat.st_ino)).map(|relpath| *relpath)
+    }
+
+    fn opendir(&mut self, relpath: &'static str) -> Result<*mut libc::DIR, c_int> {
+        if !DIRS.contains_key(relpath) {
+            return Err(libc::ENOTDIR)
+        }
+        let fd = try!(self.open(relpath, libc::O_RDONLY | O_DIRECTORY));
+        let dirp = unsafe { __real_fdopendir(fd) };
+        if dirp.is_null() {
+            let errno = get_errno();
+            unsafe { __real_close(fd) };
+            self.close(fd);
+            return Err(errno)
+        }
+        let entries = dir_entries(relpath);
+        let de = Box::new(unsafe { mem::zeroed() });
+        self.dirps.insert(dirp as usize, (fd, entries, 0, de));
+        Ok(dirp)
+    }
+    fn rewind_dirent(&mut self, dirp: *mut libc::DIR) {
+        self.dirps.get_mut(&(dirp as usize)).unwrap().2 = 0
+    }
+    fn seek_dirent(&mut self, dirp: *mut libc::DIR, loc: c_long) {
+        self.dirps.get_mut(&(dirp as usize)).unwrap().2 = loc as usize
+    }
+    fn tell_dirent(&self, dirp: *mut libc::DIR) -> c_long {
+        self.dirps[&(dirp as usize)].2 as c_long
+    }
+    // Fills in the next entry of dirp, false at the end of the stream
+    fn read_dirent(&mut self, dirp: *mut libc::DIR, de: &mut dirent) -> bool {
+        let (name, relpath, isdir, dirent_off) = {
+            let &(_, ref entries, dirent_off, _) = &self.dirps[&(dirp as usize)];
+            if dirent_off >= entries.len() {
+                return false
+            }
+            let (name, relpath, isdir) = entries[dirent_off];
+            (name, relpath, isdir, dirent_off)
+        };
+        let name_len = name.len();
+        assert!(name_len < 256);
+        *de = dirent {
+            d_ino: self.get_inode(relpath),
+            // Linux convention, the offset of the next entry
+            d_off: (dirent_off + 1) as c_long,
+            d_reclen: mem::size_of::<dirent>() as libc::c_ushort,
+            d_type: if isdir { DT_DIR } else if LINKS.contains_key(relpath) { DT_LNK } else { DT_REG },
+            d_name: [0; 256],
+        };
+        unsafe { ptr::copy(name.as_ptr() as *const c_char, de.d_name.as_mut_ptr(), name_len) };
+        self.dirps.get_mut(&(dirp as usize)).unwrap().2 = dirent_off + 1;
+        true
+    }
+    fn next_dirent(&mut self, dirp: *mut libc::DIR) -> *mut dirent {
+        let mut next: dirent = unsafe { mem::zeroed() };
+        if !self.read_dirent(dirp, &mut next) {
+            return ptr::null_mut()
+        }
+        // Boxed so the pointer stays valid as other streams come and go
+        let de = &mut self.dirps.get_mut(&(dirp as usize)).unwrap().3;
+        **de = next;
+        &mut **de as *mut dirent
+    }
+    fn closedir(&mut self, dirp: *mut libc::DIR) -> c_int {
+        let (fd, _, _, _) = self.dirps.remove(&(dirp as usize)).unwrap();
+        self.close(fd);
+        unsafe { __real_closedir(dirp) }
+    }
+
+    fn get_inode(&mut self, relpath: &'static str) -> libc::ino_t {
+        let inode = (self.inodes.len() + 1) as libc::ino_t;
+        *self.inodes.entry(relpath).or_insert(inode)
+    }
+    fn stat(&mut self, relpath: &'static str) -> libc::stat {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        stat.st_dev = 100000; // arbitrary
+        stat.st_ino = self.get_inode(relpath);
+        stat.st_nlink = 1;
+        stat.st_uid = unsafe { libc::getuid() };
+        stat.st_gid = unsafe { libc::getgid() };
+        stat.st_blksize = 4096;
+        // Nothing is writable in this backend
+        let mtime = match (FILES.get(relpath), LINKS.get(relpath)) {
+            (Some(entry), _) => {
+                stat.st_mode = libc::S_IFREG | (entry.mode & !0o222);
+                stat.st_size = entry.size as libc::off_t;
+                stat.st_blocks = ((entry.size + 1024) / 512) as libc::blkcnt_t;
+                entry.mtime
+            },
+            (None, Some(link)) => {
+                stat.st_mode = libc::S_IFLNK | 0o777;
+                stat.st_size = link.target.len() as libc::off_t;
+                link.mtime
+            },
+            (None, None) => {
+                let entry = DIRS.get(relpath).unwrap();
+                stat.st_mode = libc::S_IFDIR | (entry.mode & !0o222);
+                stat.st_nlink = 100;
+                stat.st_size = 1024;
+                entry.mtime
+            },
+        };
+        stat.st_atime = mtime;
+        stat.st_mtime = mtime;
+        stat.st_ctime = mtime;
+        stat
+    }
+}
+
+// What a listing of relpath shows, . and .. included, as name, relpath,
+// isdir. The tree never changes, so it's the same every time.
+fn dir_entries(relpath: &'static str) -> Vec<(&'static str, &'static str, bool)> {
+    // A mount root's parent is outside the tree, give it the root's inode
+    let parent = if is_mount_root(relpath) {
+        relpath
+    } else {
+        let parent = Path::new(relpath).parent().unwrap().to_str().unwrap();
+        *DIRS.get_key(parent).unwrap()
+    };
+    let mut entries = vec![(".", relpath, true), ("..", parent, true)];
+    for &(child, isdir) in DIRS[relpath].children {
+        let name = Path::new(child).file_name().unwrap().to_str().unwrap();
+        entries.push((name, child, isdir))
+    }
+    entries
+}
+
+lazy_static!{
+    static ref MEMFD_STATE: Arc<Mutex<MemfdState>> = Arc::new(Mutex::new(MemfdState {
+        memfds: HashMap::new(),
+        memfd_ids: HashMap::new(),
+        dirfds: HashMap::new(),
+        dirps: HashMap::new(),
+        inodes: HashMap::new(),
+    }));
+}
+
+fn MS<'a>() -> MutexGuard<'a, MemfdState> {
+    MEMFD_STATE.lock().unwrap()
+}
+
+fn INIT() -> bool {
+    unsafe { IS_INITIALISED }
+}
+
+// The file or directory path names, following links
+unsafe fn lookup(dirfd: c_int, path: *const c_char) -> Option<&'static str> {
+    lookup_at(dirfd, path, true)
+}
+// As lookup, but a link at the end of path is itself the result
+unsafe fn lookup_nofollow(dirfd: c_int, path: *const c_char) -> Option<&'static str> {
+    lookup_at(dirfd, path, false)
+}
+unsafe fn lookup_at(dirfd: c_int, path: *const c_char, follow: bool) -> Option<&'static str> {
+    if !INIT() {
+        return None
+    }
+    // Embedded paths are all utf-8, so anything else is passed on
+    match str::from_utf8(CStr::from_ptr(path).to_bytes()) {
+        Ok(str_path) => MS().to_relpath(dirfd, str_path, follow),
+        Err(_) => None,
+    }
+}
+
+extern {
+    fn __real_open(pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int;
+    fn __real_open64(pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int;
+    fn __real_openat(dirfd: c_int, pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int;
+    fn __real_openat64(dirfd: c_int, pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int;
+    fn __real_close(fd: c_int) -> c_int;
+    fn __real_fopen(path: *const c_char, mode: *const c_char) -> *mut libc::FILE;
+    fn __real_fopen64(path: *const c_char, mode: *const c_char) -> *mut libc::FILE;
+    fn __real_fdopen(fd: c_int, mode: *const c_char) -> *mut libc::FILE;
+    fn __real_stat(path: *const c_char, buf: *mut libc::stat) -> c_int;
+    fn __real_stat64(path: *const c_char, buf: *mut libc::stat) -> c_int;
+    fn __real___xstat(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int;
+    fn __real___xstat64(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int;
+    fn __real_lstat(path: *const c_char, buf: *mut libc::stat) -> c_int;
+    fn __real_lstat64(path: *const c_char, buf: *mut libc::stat) -> c_int;
+    fn __real___lxstat(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int;
+    fn __real___lxstat64(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int;
+    fn __real_fstat(fd: c_int, buf: *mut libc::stat) -> c_int;
+    fn __real_fstat64(fd: c_int, buf: *mut libc::stat) -> c_int;
+    fn __real___fxstat(ver: c_int, fd: c_int, buf: *mut libc::stat) -> c_int;
+    fn __real___fxstat64(ver: c_int, fd: c_int, buf: *mut libc::stat) -> c_int;
+    fn __real_access(pathname: *const c_char, mode: c_int) -> c_int;
+    fn __real_readlink(pathname: *const c_char, buf: *mut c_char, bufsiz: size_t) -> ssize_t;
+    fn __real_readlinkat(dirfd: c_int, pathname: *const c_char, buf: *mut c_char, bufsiz: size_t) -> ssize_t;
+
+    fn __real_opendir(name: *const c_char) -> *mut libc::DIR;
+    fn __real_fdopendir(fd: c_int) -> *mut libc::DIR;
+    fn __real_closedir(dirp: *mut libc::DIR) -> c_int;
+    fn __real_readdir(dirp: *mut libc::DIR) -> *mut libc::dirent_t;
+    fn __real_readdir64(dirp: *mut libc::DIR) -> *mut libc::dirent_t;
+    fn __real_readdir_r(dirp: *mut libc::DIR, entry: *mut libc::DIR, result: *mut *mut libc::DIR) -> c_int;
+    fn __real_rewinddir(dirp: *mut libc::DIR);
+    fn __real_seekdir(dirp: *mut libc::DIR, loc: c_long);
+    fn __real_telldir(dirp: *mut libc::DIR) -> c_long;
+}
+
+#[no_mangle]
+pub unsafe extern fn __wrap_open(pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+    __wrap_openat(AT_FDCWD, pathname, flags, mode)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_open64(pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+    __wrap_open(pathname, flags, mode)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_openat(dirfd: c_int, pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+    if let Some(relpath) = lookup(dirfd, pathname) {
+        return match MS().open(relpath, flags) {
+            Ok(fd) => fd,
+            Err(errno) => { set_errno(errno); -1 },
+        }
+    }
+    // Anything not embedded under a virtual directory doesn't exist, and
+    // can't be created
+    if INIT() && *pathname != b'/' as c_char && MS().dirfds.contains_key(&dirfd) {
+        set_errno(if flags & libc::O_CREAT != 0 { libc::EROFS } else { libc::ENOENT });
+        return -1
+    }
+    __real_openat(dirfd, pathname, flags, mode)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_openat64(dirfd: c_int, pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+    __wrap_openat(dirfd, pathname, flags, mode)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_close(fd: c_int) -> c_int {
+    if INIT() {
+        MS().close(fd);
+    }
+    __real_close(fd)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_fopen(path: *const c_char, mode: *const c_char) -> *mut libc::FILE {
+    if let Some(relpath) = lookup(AT_FDCWD, path) {
+        let str_mode = str::from_utf8(CStr::from_ptr(mode).to_bytes()).unwrap();
+        if !str_mode.starts_with("r") || str_mode.contains("+") {
+            set_errno(libc::EROFS);
+            return ptr::null_mut()
+        }
+        let fd = match MS().open(relpath, libc::O_RDONLY) {
+            Ok(fd) => fd,
+            Err(errno) => { set_errno(errno); return ptr::null_mut() },
+        };
+        let fp = __real_fdopen(fd, mode);
+        if fp.is_null() {
+            let errno = get_errno();
+            __wrap_close(fd);
+            set_errno(errno);
+        }
+        return fp
+    }
+    __real_fopen(path, mode)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_fopen64(path: *const c_char, mode: *const c_char) -> *mut libc::FILE {
+    __wrap_fopen(path, mode)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_stat(path: *const c_char, buf: *mut libc::stat) -> c_int {
+    if let Some(relpath) = lookup(AT_FDCWD, path) {
+        *buf = MS().stat(relpath);
+        return 0
+    }
+    __real_stat(path, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_stat64(path: *const c_char, buf: *mut libc::stat) -> c_int {
+    __wrap_stat(path, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap___xstat(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
+    if let Some(relpath) = lookup(AT_FDCWD, path) {
+        *buf = MS().stat(relpath);
+        return 0
+    }
+    __real___xstat(ver, path, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap___xstat64(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
+    __wrap___xstat(ver, path, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_lstat(path: *const c_char, buf: *mut libc::stat) -> c_int {
+    if let Some(relpath) = lookup_nofollow(AT_FDCWD, path) {
+        *buf = MS().stat(relpath);
+        return 0
+    }
+    __real_lstat(path, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_lstat64(path: *const c_char, buf: *mut libc::stat) -> c_int {
+    __wrap_lstat(path, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap___lxstat(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
+    if let Some(relpath) = lookup_nofollow(AT_FDCWD, path) {
+        *buf = MS().stat(relpath);
+        return 0
+    }
+    __real___lxstat(ver, path, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap___lxstat64(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
+    __wrap___lxstat(ver, path, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_fstat(fd: c_int, buf: *mut libc::stat) -> c_int {
+    if INIT() {
+        let mut ms = MS();
+        if let Some(relpath) = ms.fd_relpath(fd) {
+            *buf = ms.stat(relpath);
+            return 0
+        }
+    }
+    __real_fstat(fd, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_fstat64(fd: c_int, buf: *mut libc::stat) -> c_int {
+    __wrap_fstat(fd, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap___fxstat(ver: c_int, fd: c_int, buf: *mut libc::stat) -> c_int {
+    if INIT() {
+        let mut ms = MS();
+        if let Some(relpath) = ms.fd_relpath(fd) {
+            *buf = ms.stat(relpath);
+            return 0
+        }
+    }
+    __real___fxstat(ver, fd, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap___fxstat64(ver: c_int, fd: c_int, buf: *mut libc::stat) -> c_int {
+    __wrap___fxstat(ver, fd, buf)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_readlink(pathname: *const c_char, buf: *mut c_char, bufsiz: size_t) -> ssize_t {
+    __wrap_readlinkat(AT_FDCWD, pathname, buf, bufsiz)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_readlinkat(dirfd: c_int, pathname: *const c_char, buf: *mut c_char, bufsiz: size_t) -> ssize_t {
+    if let Some(relpath) = lookup_nofollow(dirfd, pathname) {
+        let target = match LINKS.get(relpath) {
+            Some(link) => link.target,
+            None => { set_errno(libc::EINVAL); return -1 },
+        };
+        // As the kernel, truncated to fit and not null terminated
+        let len = if target.len() < bufsiz as usize { target.len() } else { bufsiz as usize };
+        ptr::copy_nonoverlapping(target.as_ptr(), buf as *mut u8, len);
+        return len as ssize_t
+    }
+    __real_readlinkat(dirfd, pathname, buf, bufsiz)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_access(pathname: *const c_char, mode: c_int) -> c_int {
+    if let Some(relpath) = lookup(AT_FDCWD, pathname) {
+        if mode & !(R_OK | W_OK | X_OK) != 0 {
+            set_errno(libc::EINVAL);
+            return -1
+        }
+        if mode & W_OK != 0 {
+            set_errno(libc::EROFS);
+            return -1
+        }
+        // Everything belongs to the caller, so the owner bits decide, except
+        // for root which may read anything and execute anything with an x
+        // bit
+        let perms = MS().stat(relpath).st_mode & 0o777;
+        let wanted = mode as libc::mode_t;
+        let allowed = if libc::getuid() == 0 {
+            wanted & 0o1 == 0 || perms & 0o111 != 0
+        } else {
+            (perms >> 6) & wanted == wanted
+        };
+        if !allowed {
+            set_errno(libc::EACCES);
+            return -1
+        }
+        return 0
+    }
+    __real_access(pathname, mode)
+}
+
+#[no_mangle]
+pub unsafe extern fn __wrap_opendir(name: *const c_char) -> *mut libc::DIR {
+    if let Some(relpath) = lookup(AT_FDCWD, name) {
+        return match MS().opendir(relpath) {
+            Ok(dirp) => dirp,
+            Err(errno) => { set_errno(errno); ptr::null_mut() },
+        }
+    }
+    __real_opendir(name)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_closedir(dirp: *mut libc::DIR) -> c_int {
+    if INIT() && MS().dirps.contains_key(&(dirp as usize)) {
+        return MS().closedir(dirp)
+    }
+    __real_closedir(dirp)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_readdir(dirp: *mut libc::DIR) -> *mut libc::dirent_t {
+    if INIT() && MS().dirps.contains_key(&(dirp as usize)) {
+        return MS().next_dirent(dirp) as *mut libc::dirent_t
+    }
+    __real_readdir(dirp)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_readdir64(dirp: *mut libc::DIR) -> *mut libc::dirent_t {
+    __wrap_readdir(dirp)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_readdir_r(dirp: *mut libc::DIR, entry: *mut libc::DIR, result: *mut *mut libc::DIR) -> c_int {
+    if INIT() && MS().dirps.contains_key(&(dirp as usize)) {
+        let found = MS().read_dirent(dirp, &mut *(entry as *mut dirent));
+        *result = if found { entry } else { ptr::null_mut() };
+        return 0
+    }
+    __real_readdir_r(dirp, entry, result)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_readdir_r64(dirp: *mut libc::DIR, entry: *mut libc::DIR, result: *mut *mut libc::DIR) -> c_int {
+    __wrap_readdir_r(dirp, entry, result)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_rewinddir(dirp: *mut libc::DIR) {
+    if INIT() && MS().dirps.contains_key(&(dirp as usize)) {
+        return MS().rewind_dirent(dirp)
+    }
+    __real_rewinddir(dirp)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_seekdir(dirp: *mut libc::DIR, loc: c_long) {
+    if INIT() && MS().dirps.contains_key(&(dirp as usize)) {
+        return MS().seek_dirent(dirp, loc)
+    }
+    __real_seekdir(dirp, loc)
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_telldir(dirp: *mut libc::DIR) -> c_long {
+    if INIT() && MS().dirps.contains_key(&(dirp as usize)) {
+        return MS().tell_dirent(dirp)
+    }
+    __real_telldir(dirp)
+}
@@ -0,0 +1,173 @@
+// Contents of embedded files. Compressed ones are inflated on first access
+// and kept in a cache bounded to PYINRS_CACHE_SIZE bytes (default 64MB),
+// evicting the least recently used, plus the last file read that's bigger
+// than that. Readers hold their own reference, so eviction never pulls data
+// out from under them.
+
+use std::env;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use libc::{c_int, c_ulong};
+
+use super::FileEntry;
+
+const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+const Z_OK: c_int = 0;
+
+extern {
+    // From the zlib linked into the binary
+    fn uncompress(dest: *mut u8, dest_len: *mut c_ulong, source: *const u8, source_len: c_ulong) -> c_int;
+}
+
+pub enum Contents<'a> {
+    Borrowed(&'a [u8]),
+    Inflated(Arc<Vec<u8>>),
+}
+
+impl<'a> Deref for Contents<'a> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Contents::Borrowed(data) => data,
+            Contents::Inflated(ref data) => &data[..],
+        }
+    }
+}
+
+struct Cache {
+    // data address -> contents, last use
+    entries: HashMap<usize, (Arc<Vec<u8>>, usize)>,
+    total: usize,
+    limit: usize,
+    clock: usize,
+    // data address -> contents, for the last file read that's too big for
+    // the cache, so reading it in pieces only inflates it once
+    oversize: Option<(usize, Arc<Vec<u8>>)>,
+}
+
+lazy_static!{
+    static ref CACHE: Mutex<Cache> = Mutex::new(Cache::new(
+        env::var("PYINRS_CACHE_SIZE").ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_CACHE_SIZE)));
+}
+
+pub fn contents(entry: &'static FileEntry) -> Contents<'static> {
+    if !entry.compressed {
+        return Contents::Borrowed(entry.data)
+    }
+    let key = entry.data.as_ptr() as usize;
+    Contents::Inflated(CACHE.lock().unwrap().get(key, entry.size, || inflate(entry)))
+}
+
+impl Cache {
+    fn new(limit: usize) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            total: 0,
+            limit: limit,
+            clock: 0,
+            oversize: None,
+        }
+    }
+
+    // The contents cached under key, inflating them if they aren't
+    fn get<F: FnOnce() -> Vec<u8>>(&mut self, key: usize, size: usize, inflate: F) -> Arc<Vec<u8>> {
+        self.clock += 1;
+        let now = self.clock;
+        if let Some(&mut (ref data, ref mut last_use)) = self.entries.get_mut(&key) {
+            *last_use = now;
+            return data.clone()
+        }
+        if let Some((oversize_key, ref data)) = self.oversize {
+            if oversize_key == key {
+                return data.clone()
+            }
+        }
+        let data = Arc::new(inflate());
+        // A file bigger than the whole cache is still handed out, just kept
+        // apart until another one like it comes along
+        if size > self.limit {
+            self.oversize = Some((key, data.clone()));
+            return data
+        }
+        while self.total + size > self.limit {
+            let oldest = *self.entries.iter()
+                .min_by(|&(_, &(_, last_use))| last_use)
+                .unwrap().0;
+            let (evicted, _) = self.entries.remove(&oldest).unwrap();
+            self.total -= evicted.len();
+        }
+        self.total += size;
+        self.entries.insert(key, (data.clone(), now));
+        data
+    }
+}
+
+fn inflate(entry: &FileEntry) -> Vec<u8> {
+    let mut data = Vec::with_capacity(entry.size);
+    let mut len = entry.size as c_ulong;
+    let ret = unsafe {
+        uncompress(data.as_mut_ptr(), &mut len, entry.data.as_ptr(), entry.data.len() as c_ulong)
+    };
+    assert!(ret == Z_OK && len as usize == entry.size, "corrupt embedded file");
+    unsafe { data.set_len(entry.size) };
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use super::Cache;
+
+    // Gets key from the cache, counting the times it had to be inflated
+    fn get(cache: &mut Cache, key: usize, size: usize, inflated: &Cell<usize>) -> Vec<u8> {
+        (*cache.get(key, size, || { inflated.set(inflated.get() + 1); vec![key as u8; size] })).clone()
+    }
+
+    #[test]
+    fn hits_are_not_inflated_again() {
+        let mut cache = Cache::new(10);
+        let inflated = Cell::new(0);
+        assert_eq!(get(&mut cache, 1, 4, &inflated), vec![1u8; 4]);
+        assert_eq!(get(&mut cache, 1, 4, &inflated), vec![1u8; 4]);
+        assert_eq!(inflated.get(), 1);
+        assert_eq!(cache.total, 4);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let mut cache = Cache::new(10);
+        let inflated = Cell::new(0);
+        get(&mut cache, 1, 4, &inflated);
+        get(&mut cache, 2, 4, &inflated);
+        get(&mut cache, 1, 4, &inflated);
+        // Needs room, so 2 goes and 1 stays
+        get(&mut cache, 3, 4, &inflated);
+        assert_eq!(inflated.get(), 3);
+        assert!(cache.entries.contains_key(&1) && cache.entries.contains_key(&3));
+        assert!(!cache.entries.contains_key(&2));
+        assert_eq!(cache.total, 8);
+        get(&mut cache, 2, 4, &inflated);
+        assert_eq!(inflated.get(), 4);
+        assert!(cache.total <= cache.limit);
+    }
+
+    #[test]
+    fn oversize_files_get_their_own_slot() {
+        let mut cache = Cache::new(10);
+        let inflated = Cell::new(0);
+        get(&mut cache, 1, 4, &inflated);
+        assert_eq!(get(&mut cache, 2, 20, &inflated), vec![2u8; 20]);
+        assert_eq!(get(&mut cache, 2, 20, &inflated), vec![2u8; 20]);
+        assert_eq!(inflated.get(), 2);
+        // Kept out of the cache proper, evicting nothing
+        assert!(cache.entries.contains_key(&1) && !cache.entries.contains_key(&2));
+        assert_eq!(cache.total, 4);
+        // The next one replaces it
+        get(&mut cache, 3, 20, &inflated);
+        get(&mut cache, 2, 20, &inflated);
+        assert_eq!(inflated.get(), 4);
+    }
+}
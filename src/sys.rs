@@ -0,0 +1,329 @@
+// Pieces of the libc and kernel interface shared between backends
+
+use libc;
+use libc::{c_void, c_int, c_uint, c_char, off_t};
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+use std::{env, mem};
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+use std::ffi::CString;
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+use std::sync::Mutex;
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+use libc::{c_long, size_t};
+
+// Missing defines from libc crate
+pub const AT_FDCWD: c_int = -100;
+pub const AT_SYMLINK_NOFOLLOW: c_int = 0x100;
+pub const AT_REMOVEDIR: c_int = 0x200;
+pub const AT_EMPTY_PATH: c_int = 0x1000;
+pub const RENAME_NOREPLACE: c_uint = 1;
+pub const O_ACCMODE: c_int = 0o3;
+pub const O_DIRECTORY: c_int = 0o200000;
+pub const O_CLOEXEC: c_int = 0o2000000;
+pub const MAP_ANONYMOUS: c_int = 0x20;
+pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+pub const DT_DIR: libc::c_uchar = 4;
+pub const DT_REG: libc::c_uchar = 8;
+pub const DT_LNK: libc::c_uchar = 10;
+pub const PATH_MAX: usize = 4096;
+pub const R_OK: c_int = 4;
+pub const W_OK: c_int = 2;
+pub const X_OK: c_int = 1;
+pub const EOPNOTSUPP: c_int = 95;
+#[allow(dead_code)]
+#[allow(non_camel_case_types)]
+pub struct dirent {
+    pub d_ino: libc::ino_t,
+    pub d_off: off_t,
+    pub d_reclen: libc::c_ushort,
+    pub d_type: libc::c_uchar,
+    pub d_name: [c_char; 256],
+}
+
+// Not in libc at all yet
+pub const STATX_BASIC_STATS: c_uint = 0x7ff;
+#[allow(dead_code)]
+#[allow(non_camel_case_types)]
+pub struct statx_timestamp {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub __reserved: i32,
+}
+#[allow(dead_code)]
+#[allow(non_camel_case_types)]
+pub struct statx {
+    pub stx_mask: u32,
+    pub stx_blksize: u32,
+    pub stx_attributes: u64,
+    pub stx_nlink: u32,
+    pub stx_uid: u32,
+    pub stx_gid: u32,
+    pub stx_mode: u16,
+    pub __spare0: [u16; 1],
+    pub stx_ino: u64,
+    pub stx_size: u64,
+    pub stx_blocks: u64,
+    pub stx_attributes_mask: u64,
+    pub stx_atime: statx_timestamp,
+    pub stx_btime: statx_timestamp,
+    pub stx_ctime: statx_timestamp,
+    pub stx_mtime: statx_timestamp,
+    pub stx_rdev_major: u32,
+    pub stx_rdev_minor: u32,
+    pub stx_dev_major: u32,
+    pub stx_dev_minor: u32,
+    pub __spare2: [u64; 14],
+}
+
+// Syscall numbers differ between architectures, and only x86_64's are known
+// here - anywhere else the import below fails to build rather than making
+// the wrong calls. Only the backends wrapping libc make raw syscalls, so
+// dump builds anywhere.
+#[cfg(all(target_arch = "x86_64", any(feature = "wrap", feature = "memfd")))]
+#[allow(non_upper_case_globals)]
+mod nr {
+    use libc::c_long;
+    pub const SYS_write: c_long = 1;
+    pub const SYS_open: c_long = 2;
+    pub const SYS_close: c_long = 3;
+    pub const SYS_fstat: c_long = 5;
+    pub const SYS_lseek: c_long = 8;
+    pub const SYS_fcntl: c_long = 72;
+    pub const SYS_mkdir: c_long = 83;
+    pub const SYS_rmdir: c_long = 84;
+    pub const SYS_memfd_create: c_long = 319;
+}
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+use self::nr::*;
+
+pub const MFD_CLOEXEC: c_uint = 1;
+const MFD_ALLOW_SEALING: c_uint = 2;
+const F_ADD_SEALS: c_int = 1033;
+const F_SEAL_SEAL: c_int = 1;
+const F_SEAL_SHRINK: c_int = 2;
+const F_SEAL_GROW: c_int = 4;
+const F_SEAL_WRITE: c_int = 8;
+const F_DUPFD: c_int = 0;
+const F_SETFD: c_int = 2;
+const F_DUPFD_CLOEXEC: c_int = 1030;
+
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+lazy_static!{
+    // fd, device, inode of the directory dir_placeholder copies
+    static ref DEAD_DIR: Mutex<Option<(c_int, libc::dev_t, libc::ino_t)>> = Mutex::new(None);
+}
+
+extern {
+    fn __errno_location() -> *mut c_int;
+}
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+extern {
+    fn syscall(num: c_long, ...) -> c_long;
+}
+
+pub fn set_errno(errno: c_int) {
+    unsafe { *__errno_location() = errno }
+}
+pub fn get_errno() -> c_int {
+    unsafe { *__errno_location() }
+}
+
+// These go straight to the kernel rather than through libc, so they're safe
+// to call from inside wrappers without recursing into them
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+pub fn open(path: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+    unsafe { syscall(SYS_open, path, flags, mode) as c_int }
+}
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+pub fn close(fd: c_int) -> c_int {
+    unsafe { syscall(SYS_close, fd) as c_int }
+}
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+pub fn lseek(fd: c_int, offset: off_t, whence: c_int) -> off_t {
+    unsafe { syscall(SYS_lseek, fd, offset, whence) as off_t }
+}
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+fn write(fd: c_int, buf: &[u8]) -> isize {
+    unsafe { syscall(SYS_write, fd, buf.as_ptr() as *const c_void, buf.len() as size_t) as isize }
+}
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+fn fcntl(fd: c_int, cmd: c_int, arg: c_int) -> c_int {
+    unsafe { syscall(SYS_fcntl, fd, cmd, arg) as c_int }
+}
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+pub fn fstat(fd: c_int, buf: *mut libc::stat) -> c_int {
+    unsafe { syscall(SYS_fstat, fd, buf) as c_int }
+}
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+fn mkdir(path: *const c_char, mode: libc::mode_t) -> c_int {
+    unsafe { syscall(SYS_mkdir, path, mode) as c_int }
+}
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+fn rmdir(path: *const c_char) -> c_int {
+    unsafe { syscall(SYS_rmdir, path) as c_int }
+}
+// Lets fd survive an exec
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+pub fn clear_cloexec(fd: c_int) -> c_int {
+    fcntl(fd, F_SETFD, 0)
+}
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+pub fn write_all(fd: c_int, buf: &[u8]) -> Result<(), c_int> {
+    let mut written = 0;
+    while written < buf.len() {
+        let ret = write(fd, &buf[written..]);
+        if ret < 0 {
+            let errno = get_errno();
+            if errno == libc::EINTR {
+                continue
+            }
+            return Err(errno)
+        }
+        written += ret as usize;
+    }
+    Ok(())
+}
+
+// The interpreter and argument (empty if there isn't one) from a #! line,
+// None if it names no interpreter. As Linux, the argument is the rest of
+// the line, blanks trimmed but otherwise as is.
+pub fn parse_shebang(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    if !data.starts_with(b"#!") {
+        return None
+    }
+    let is_blank = |b: &u8| *b == b' ' || *b == b'\t';
+    let line = data[2..].split(|b| *b == b'\n').next().unwrap();
+    let line = &line[line.iter().position(|b| !is_blank(b)).unwrap_or(line.len())..];
+    let (interp, arg) = line.split_at(line.iter().position(|b| is_blank(b)).unwrap_or(line.len()));
+    let arg = &arg[arg.iter().position(|b| !is_blank(b)).unwrap_or(arg.len())..];
+    let arg = &arg[..arg.iter().rposition(|b| !is_blank(b)).map_or(0, |i| i + 1)];
+    if interp.is_empty() {
+        return None
+    }
+    Some((interp, arg))
+}
+
+// memfd_create in kernel 3.17
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+pub fn memfd_create(name: &str, flags: c_uint) -> Result<c_int, c_int> {
+    let name_cstr = CString::new(name).unwrap();
+    let fd = unsafe { syscall(SYS_memfd_create, name_cstr.as_ptr(), flags) };
+    if fd < 0 { Err(get_errno()) } else { Ok(fd as c_int) }
+}
+
+// A memfd holding data, sealed so nobody can change it, with the offset at
+// the start
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+pub fn memfd_from_data(name: &str, data: &[u8], flags: c_uint) -> Result<c_int, c_int> {
+    let fd = try!(memfd_create(name, flags | MFD_ALLOW_SEALING));
+    if let Err(errno) = write_all(fd, data) {
+        close(fd);
+        return Err(errno)
+    }
+    let seals = F_SEAL_SEAL | F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_WRITE;
+    if fcntl(fd, F_ADD_SEALS, seals) < 0 || lseek(fd, 0, libc::SEEK_SET) < 0 {
+        let errno = get_errno();
+        close(fd);
+        return Err(errno)
+    }
+    Ok(fd)
+}
+
+// A directory fd standing in for a virtual directory. It's a private
+// directory removed straight after opening, so the kernel refuses to look up
+// or create anything relative to it - calls that aren't wrapped can't reach
+// the host filesystem through the placeholder. The lowest available number is
+// picked, as open would.
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+pub fn dir_placeholder(flags: c_int) -> Result<c_int, c_int> {
+    let mut dead_dir = DEAD_DIR.lock().unwrap();
+    // The application may have closed the original (closerange, or a child
+    // closing everything before exec), and its number may since have been
+    // reused, so it's made again if what's there now isn't it
+    let current = match *dead_dir {
+        Some((fd, dev, ino)) => {
+            let mut stat: libc::stat = unsafe { mem::zeroed() };
+            fstat(fd, &mut stat) == 0 && stat.st_dev == dev && stat.st_ino == ino
+        },
+        None => false,
+    };
+    if !current {
+        *dead_dir = Some(try!(make_dead_dir()))
+    }
+    let (dead_fd, _, _) = dead_dir.unwrap();
+    let cmd = if flags & O_CLOEXEC != 0 { F_DUPFD_CLOEXEC } else { F_DUPFD };
+    let fd = fcntl(dead_fd, cmd, 0);
+    if fd < 0 { Err(get_errno()) } else { Ok(fd) }
+}
+
+// Makes and removes a directory in $TMPDIR, or /tmp if that's unset, so
+// directory placeholders need somewhere writable there
+#[cfg(any(feature = "wrap", feature = "memfd"))]
+fn make_dead_dir() -> Result<(c_int, libc::dev_t, libc::ino_t), c_int> {
+    let tmpdir = match env::var("TMPDIR") {
+        Ok(ref dir) if dir.starts_with("/") => dir.clone(),
+        _ => String::from("/tmp"),
+    };
+    let pid = unsafe { libc::getpid() };
+    for attempt in 0..100 {
+        let path = match CString::new(format!("{}/.pyinrs-{}-{}", tmpdir, pid, attempt)) {
+            Ok(path) => path,
+            Err(_) => return Err(libc::EINVAL),
+        };
+        if mkdir(path.as_ptr(), 0o700) < 0 {
+            let errno = get_errno();
+            if errno == libc::EEXIST { continue }
+            return Err(errno)
+        }
+        let fd = open(path.as_ptr(), libc::O_RDONLY | O_DIRECTORY | O_CLOEXEC, 0);
+        let errno = get_errno();
+        if rmdir(path.as_ptr()) < 0 {
+            let errno = get_errno();
+            if fd >= 0 { close(fd); }
+            return Err(errno)
+        }
+        if fd < 0 {
+            return Err(errno)
+        }
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if fstat(fd, &mut stat) < 0 {
+            let errno = get_errno();
+            close(fd);
+            return Err(errno)
+        }
+        return Ok((fd, stat.st_dev, stat.st_ino))
+    }
+    Err(libc::EEXIST)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_shebang;
+
+    fn parse(data: &str) -> Option<(&str, &str)> {
+        parse_shebang(data.as_bytes()).map(|(interp, arg)| {
+            (::std::str::from_utf8(interp).unwrap(), ::std::str::from_utf8(arg).unwrap())
+        })
+    }
+
+    #[test]
+    fn shebang_interp_only() {
+        assert_eq!(parse("#!/bin/sh\necho hi\n"), Some(("/bin/sh", "")));
+        assert_eq!(parse("#!/bin/sh"), Some(("/bin/sh", "")));
+        assert_eq!(parse("#! \t/bin/sh \t\n"), Some(("/bin/sh", "")));
+    }
+
+    #[test]
+    fn shebang_arg_is_rest_of_line() {
+        assert_eq!(parse("#!/usr/bin/env python -u\n"), Some(("/usr/bin/env", "python -u")));
+        assert_eq!(parse("#! /bin/sh \t -e  -x \t\nset -e\n"), Some(("/bin/sh", "-e  -x")));
+    }
+
+    #[test]
+    fn shebang_without_interp() {
+        assert_eq!(parse("#!\n/bin/sh\n"), None);
+        assert_eq!(parse("#!  \t"), None);
+        assert_eq!(parse("echo hi\n"), None);
+        assert_eq!(parse(""), None);
+    }
+}
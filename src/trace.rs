@@ -0,0 +1,188 @@
+// Logging of intercepted calls, for debugging what an application asks of
+// the embedded files. Set PYINRS_TRACE=1 to enable, records go to stderr or
+// to the file named by PYINRS_TRACE_FILE.
+
+use std::cell::Cell;
+use std::env;
+use std::ffi::{CStr, CString};
+use libc;
+use libc::{c_void, c_int, c_char};
+
+use super::sys;
+use super::sys::{set_errno, get_errno, O_CLOEXEC};
+
+static mut TRACE_FD: c_int = -1;
+
+// Wrappers calling other wrappers (fopen64 -> fopen, creat -> open...) only
+// show up once, under the name the application used
+thread_local!(static DEPTH: Cell<usize> = Cell::new(0));
+// Whether the wrappers took a virtual branch during the outermost call
+thread_local!(static SERVED: Cell<bool> = Cell::new(false));
+
+pub enum Arg {
+    Path(*const c_char),
+    Str(*const c_char),
+    Fd(c_int),
+    Fp(*mut libc::FILE),
+    Dirp(*mut libc::DIR),
+    Int(i64),
+    Flags(c_int),
+    Mode(libc::mode_t),
+    Ptr(*const c_void),
+}
+
+// Must be called before any wrapper is live, as opening the trace file
+// would otherwise go through them
+pub fn init() {
+    match env::var("PYINRS_TRACE") {
+        Ok(ref val) if val != "" && val != "0" => (),
+        _ => return,
+    }
+    let fd = match env::var("PYINRS_TRACE_FILE") {
+        Ok(path) => {
+            let cpath = CString::new(path).unwrap();
+            let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND | O_CLOEXEC;
+            let fd = sys::open(cpath.as_ptr(), flags, 0o644);
+            if fd < 0 {
+                panic!("could not open trace file, errno {}", get_errno())
+            }
+            fd
+        },
+        Err(_) => 2,
+    };
+    unsafe { TRACE_FD = fd }
+}
+
+pub fn enabled() -> bool {
+    unsafe { TRACE_FD >= 0 }
+}
+
+// Called by a wrapper as it decides to serve the call itself, rather than
+// passing it on to libc
+pub fn served_virtually() {
+    if enabled() {
+        SERVED.with(|served| served.set(true))
+    }
+}
+
+pub struct Call {
+    // Whether this call counts towards DEPTH
+    started: bool,
+    // Line so far, None if this call isn't being recorded
+    line: Option<String>,
+}
+
+impl Call {
+    pub fn none() -> Call {
+        Call { started: false, line: None }
+    }
+    pub fn start(name: &str, args: &[Arg]) -> Call {
+        let outermost = DEPTH.with(|depth| {
+            depth.set(depth.get() + 1);
+            depth.get() == 1
+        });
+        if !outermost {
+            return Call { started: true, line: None }
+        }
+        SERVED.with(|served| served.set(false));
+        let args: Vec<String> = args.iter().map(format_arg).collect();
+        let line = format!("pyinrs: {}({})", name, args.join(", "));
+        Call { started: true, line: Some(line) }
+    }
+    pub fn finish<T: TraceRet>(self, ret: T) -> T {
+        if self.started {
+            DEPTH.with(|depth| depth.set(depth.get() - 1))
+        }
+        let line = match self.line {
+            Some(line) => line,
+            None => return ret,
+        };
+        let errno = get_errno();
+        let served = if SERVED.with(|served| served.get()) { "virtual" } else { "real" };
+        let line = format!("{} [{}]", line, served);
+        let line = match ret.describe() {
+            Some((ref val, true)) => format!("{} = {} ({})\n", line, val, errno_name(errno)),
+            Some((ref val, false)) => format!("{} = {}\n", line, val),
+            None => format!("{}\n", line),
+        };
+        let _ = sys::write_all(unsafe { TRACE_FD }, line.as_bytes());
+        set_errno(errno);
+        ret
+    }
+}
+
+fn format_arg(arg: &Arg) -> String {
+    match *arg {
+        Arg::Path(s) | Arg::Str(s) => {
+            if s.is_null() {
+                String::from("NULL")
+            } else {
+                let bytes = unsafe { CStr::from_ptr(s).to_bytes() };
+                format!("{:?}", String::from_utf8_lossy(bytes))
+            }
+        },
+        Arg::Fd(fd) => format!("{}", fd),
+        Arg::Fp(fp) => format!("{:?}", fp),
+        Arg::Dirp(dirp) => format!("{:?}", dirp),
+        Arg::Int(val) => format!("{}", val),
+        Arg::Flags(flags) => format!("{:#x}", flags),
+        Arg::Mode(mode) => format!("{:#o}", mode),
+        Arg::Ptr(ptr) => format!("{:?}", ptr),
+    }
+}
+
+fn errno_name(errno: c_int) -> String {
+    let name = match errno {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::EINTR => "EINTR",
+        libc::EIO => "EIO",
+        libc::EBADF => "EBADF",
+        libc::ENOMEM => "ENOMEM",
+        libc::EACCES => "EACCES",
+        libc::EBUSY => "EBUSY",
+        libc::EEXIST => "EEXIST",
+        libc::EXDEV => "EXDEV",
+        libc::ENODEV => "ENODEV",
+        libc::ENOTDIR => "ENOTDIR",
+        libc::EISDIR => "EISDIR",
+        libc::EINVAL => "EINVAL",
+        libc::EMFILE => "EMFILE",
+        libc::ESPIPE => "ESPIPE",
+        libc::EROFS => "EROFS",
+        libc::ERANGE => "ERANGE",
+        libc::ENAMETOOLONG => "ENAMETOOLONG",
+        libc::ENOSYS => "ENOSYS",
+        libc::ENOTEMPTY => "ENOTEMPTY",
+        libc::ELOOP => "ELOOP",
+        _ => return format!("errno {}", errno),
+    };
+    String::from(name)
+}
+
+// How a wrapper's return value reads in a record, and whether it says the
+// call failed so errno is worth showing
+pub trait TraceRet {
+    fn describe(&self) -> Option<(String, bool)>;
+}
+impl TraceRet for () {
+    fn describe(&self) -> Option<(String, bool)> { None }
+}
+impl TraceRet for i32 {
+    fn describe(&self) -> Option<(String, bool)> { Some((format!("{}", self), *self < 0)) }
+}
+impl TraceRet for i64 {
+    fn describe(&self) -> Option<(String, bool)> { Some((format!("{}", self), *self < 0)) }
+}
+impl TraceRet for isize {
+    fn describe(&self) -> Option<(String, bool)> { Some((format!("{}", self), *self < 0)) }
+}
+impl TraceRet for u64 {
+    fn describe(&self) -> Option<(String, bool)> { Some((format!("{}", self), false)) }
+}
+impl TraceRet for usize {
+    fn describe(&self) -> Option<(String, bool)> { Some((format!("{}", self), false)) }
+}
+impl<T> TraceRet for *mut T {
+    fn describe(&self) -> Option<(String, bool)> { Some((format!("{:?}", *self), self.is_null())) }
+}
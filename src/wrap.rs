@@ -2,280 +2,1163 @@
 
 use std::ffi::{CStr, CString};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::ptr;
+use std::cmp;
+use std::mem;
 use std::str;
-use std::env;
+use std::iter;
+use std::slice;
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use libc;
-use libc::{c_void, c_int, c_long, c_char, size_t, ssize_t, off_t, fpos_t};
+use libc::{c_void, c_int, c_uint, c_long, c_char, size_t, ssize_t, off_t, fpos_t};
 
-use super::{FILES, DIRS, WORKDIR};
+use super::{FILES, DIRS, LINKS, path_key, resolve_key, key_path, mount_root, is_mount_root};
+use super::store;
+use super::store::Contents;
+use super::sys;
+use super::trace;
+use super::trace::Arg;
+use super::sys::{DT_DIR, DT_REG, DT_LNK, PATH_MAX, R_OK, W_OK, X_OK, EOPNOTSUPP};
+use super::sys::{dirent, set_errno, get_errno, AT_FDCWD, O_ACCMODE, O_DIRECTORY, O_CLOEXEC};
+use super::sys::{AT_SYMLINK_NOFOLLOW, AT_REMOVEDIR, AT_EMPTY_PATH, RENAME_NOREPLACE, STATX_BASIC_STATS};
+use super::sys::{MAP_ANONYMOUS, MAP_FAILED};
 
 static mut IS_INITIALISED: bool = false;
 
-// Missing defines from libc crate
-const AT_FDCWD: c_int = -100;
-#[allow(dead_code)]
-#[allow(non_camel_case_types)]
-struct dirent {
-    d_ino: libc::ino_t,
-    d_off: off_t,
-    d_reclen: libc::c_ushort,
-    d_type: libc::c_uchar,
-    d_name: [c_char; 256],
-}
-
 pub fn prep() {
-    // Make sure FS is initialised
+    trace::init();
+    // Make sure FS and VIRTUAL_FDS are initialised
     assert!(!FS().exists("non_existent_file"));
+    assert!(!maybe_virtual_fd(0));
+    // Files made in the overlay take their mode from it, as on disk
+    let mask = unsafe { __real_umask(0) };
+    unsafe { __real_umask(mask) };
+    FS().umask = mask;
     unsafe { IS_INITIALISED = true };
 }
 
 pub fn atexit() {}
 
-// memfd_create in kernel 3.17
+// The tree as the application sees it, overlay included, for the importer.
+// Keys are as resolve_key gives them.
+pub fn key_is_file(key: &str) -> bool {
+    FS().is_file(key)
+}
+pub fn key_is_dir(key: &str) -> bool {
+    FS().is_dir(key)
+}
+pub fn key_link_target(key: &str) -> Option<String> {
+    FS().link_target(key)
+}
+pub fn key_contents(key: &str) -> Option<Contents<'static>> {
+    let fs = FS();
+    match fs.node_at(key) {
+        Some(Node::Embedded(key)) => Some(store::contents(&FILES[key])),
+        Some(Node::Overlay(node)) => Some(Contents::Inflated(Arc::new(fs.nodes[&node].clone()))),
+        _ => None,
+    }
+}
+
+// Operations on virtual files that aren't emulated. These fail like libc
+// would, unless built with the failfast feature for finding the gaps.
+macro_rules! unsupported {
+    ($name:expr, $errno:expr, $ret:expr) => {{
+        if cfg!(feature = "failfast") {
+            panic!("unsupported on virtual file: {}", $name)
+        }
+        set_errno($errno);
+        return $ret
+    }}
+}
+
+// Runs a wrapper body, recording the call when PYINRS_TRACE is set. Bodies
+// note when they serve the call virtually (see served).
+macro_rules! traced {
+    ($name:expr, [$($kind:ident($arg:expr)),*], $body:block) => {{
+        let call = if trace::enabled() {
+            trace::Call::start($name, &[$(Arg::$kind($arg)),*])
+        } else {
+            trace::Call::none()
+        };
+        let ret = (move || $body)();
+        call.finish(ret)
+    }}
+}
 
+// Every virtual fd, fp and dirp is backed by a real one reserved from the
+// kernel (see reserve_fd), so the numbers and pointers handed out are valid
+// everywhere even though the wrappers serve the data.
 struct FileState {
-    cwd: Option<&'static str>,
-    // path, offset
-    fds: Vec<(&'static str, usize)>,
-    // fd, eof
-    fps: Vec<(c_int, bool)>,
-    base_fp: usize,
-    base_fd: usize,
+    // relpath
+    cwd: Option<String>,
+    // fd -> open file description
+    fds: HashMap<c_int, usize>,
+    // Open file descriptions, shared between fds by dup as in the kernel,
+    // slots are freed when the last fd goes and reused by later opens
+    // relpath, offset, open flags, node
+    descs: Vec<Option<(String, usize, c_int, Node)>>,
+    // fp -> fd, eof, access mode (O_RDONLY etc, which may be narrower
+    // than the fd's)
+    fps: HashMap<usize, (c_int, bool, c_int)>,
     // for storing memory for dirent calls
-    // next_offset, cur_dirent
-    dirents: Vec<(usize, dirent)>,
+    // Entries are snapshotted at opendir and rewinddir, so offsets from
+    // telldir stay meaningful while the overlay changes underneath
+    // dirp -> fd, entries, next_offset, cur_dirent
+    dirps: HashMap<usize, (c_int, Vec<(String, bool)>, usize, Box<dirent>)>,
+    // fps and dirps we've closed, so closing again gives EBADF rather than
+    // handing freed memory to libc. Forgotten once libc hands the address out
+    // again, which all the wrapped calls making FILEs and DIRs watch for.
+    closed_handles: HashSet<usize>,
     // for stat calls
-    inodes: HashMap<&'static str, libc::ino_t>,
+    inodes: HashMap<String, libc::ino_t>,
     base_inode: usize,
+    // Copy-on-write layer over FILES and DIRS. Anything written to or
+    // created under the workdir lives here, and whiteouts hide entries in
+    // FILES and DIRS that have since been unlinked or renamed away.
+    // relpath -> node
+    overlay_files: HashMap<String, usize>,
+    overlay_dirs: HashSet<String>,
+    // relpath -> target, for symlinks that have been renamed
+    overlay_links: HashMap<String, String>,
+    whiteouts: HashSet<String>,
+    // relpath -> mtime, permission bits, for overlay entries whose metadata
+    // differs from (or doesn't exist in) FILES and DIRS
+    overlay_meta: HashMap<String, (i64, libc::mode_t)>,
+    // relpath -> memfd for libraries handed to the dynamic loader
+    dl_memfds: HashMap<String, c_int>,
+    // Applied to the modes files and directories are created with, kept in
+    // step with the real one by the umask wrapper
+    umask: libc::mode_t,
+    // node -> contents of files in the overlay, kept while a path or an open
+    // description refers to them
+    nodes: HashMap<usize, Vec<u8>>,
+    next_node: usize,
+}
+
+// What an open file description reads and writes, so it keeps its file
+// through renames and unlinks, and never sees a new file made at its path.
+// Embedded files are read in place until something copies them up, which
+// moves their descriptions over to the overlay node.
+#[derive(Clone, Copy, PartialEq)]
+enum Node {
+    Embedded(&'static str),
+    Overlay(usize),
+    Dir,
+}
+
+fn now() -> i64 {
+    unsafe { libc::time(ptr::null_mut()) as i64 }
+}
+
+// As Linux's limit on #! interpreters that are scripts themselves
+const MAXINTERP: usize = 4;
+
+fn join_relpath(dir: &str, name: &str) -> String {
+    if dir == "" { String::from(name) } else { format!("{}/{}", dir, name) }
 }
 
 impl FileState {
+    fn new() -> FileState {
+        FileState {
+            cwd: None,
+            fds: HashMap::new(),
+            descs: vec![],
+            fps: HashMap::new(),
+            dirps: HashMap::new(),
+            closed_handles: HashSet::new(),
+            inodes: HashMap::new(),
+            base_inode: 0,
+            overlay_files: HashMap::new(),
+            overlay_dirs: HashSet::new(),
+            overlay_links: HashMap::new(),
+            whiteouts: HashSet::new(),
+            overlay_meta: HashMap::new(),
+            dl_memfds: HashMap::new(),
+            umask: 0o022,
+            nodes: HashMap::new(),
+            next_node: 0,
+        }
+    }
+
+    // A path that loops is in the tree too, so the caller gets ELOOP rather
+    // than falling through to the real filesystem
     fn exists(&self, fpath: &str) -> bool {
-        let workdir = unsafe { WORKDIR };
-        let mut path = self.actual_cwd_path();
-        path.push(Path::new(fpath));
-        let relresult = path.relative_from(workdir);
-        // Not in workdir?
-        if relresult.is_none() {
+        match self.lookup(fpath, true) {
+            Ok(relpath) => self.is_file(&relpath) || self.is_dir(&relpath),
+            Err(errno) => errno == libc::ELOOP,
+        }
+    }
+    // As exists, but a link at the end of fpath counts itself
+    fn exists_nofollow(&self, fpath: &str) -> bool {
+        match self.lookup(fpath, false) {
+            Ok(relpath) => self.is_file(&relpath) || self.is_dir(&relpath) || self.is_link(&relpath),
+            Err(errno) => errno == libc::ELOOP,
+        }
+    }
+    // Whether fpath doesn't exist yet but would be created in the overlay
+    fn can_create(&self, fpath: &str) -> bool {
+        let relpath = match self.to_relpath(fpath) {
+            Some(relpath) => relpath,
+            None => return false,
+        };
+        if self.is_file(&relpath) || self.is_dir(&relpath) || self.is_link(&relpath) {
             return false
         }
-        // Filename?
-        let relpath_str = relresult.unwrap().to_str().unwrap();
-        if FILES.get_key(relpath_str).is_some() {
-            true
-        } else if DIRS.contains(relpath_str) {
-            true
-        } else {
-            false
+        match Path::new(&relpath).parent() {
+            Some(parent) => self.is_dir(parent.to_str().unwrap()),
+            None => false,
+        }
+    }
+    fn is_file(&self, relpath: &str) -> bool {
+        self.overlay_files.contains_key(relpath) ||
+            (!self.whiteouts.contains(relpath) && FILES.contains_key(relpath))
+    }
+    fn is_dir(&self, relpath: &str) -> bool {
+        self.overlay_dirs.contains(relpath) ||
+            (!self.whiteouts.contains(relpath) && DIRS.contains_key(relpath))
+    }
+    // Files and dirs written over an embedded link shadow it
+    fn is_link(&self, relpath: &str) -> bool {
+        self.overlay_links.contains_key(relpath) ||
+            (!self.whiteouts.contains(relpath) && LINKS.contains_key(relpath) &&
+             !self.overlay_files.contains_key(relpath) && !self.overlay_dirs.contains(relpath))
+    }
+    fn link_target(&self, relpath: &str) -> Option<String> {
+        if !self.is_link(relpath) {
+            return None
+        }
+        match self.overlay_links.get(relpath) {
+            Some(target) => Some(target.clone()),
+            None => Some(String::from(LINKS[relpath].target)),
         }
     }
     fn is_fd(&self, fd: c_int) -> bool {
-        let fd = fd as usize;
-        fd >= self.base_fd && fd < self.base_fd + self.fds.len()
+        self.fds.contains_key(&fd)
     }
     fn is_fp(&self, fp: *mut libc::FILE) -> bool {
-        let fp = fp as usize;
-        fp >= self.base_fp && fp < self.base_fp + self.fps.len()
+        self.fps.contains_key(&(fp as usize))
+    }
+    fn is_dirp(&self, dirp: *mut libc::DIR) -> bool {
+        self.dirps.contains_key(&(dirp as usize))
     }
 
-    fn get_fp_data(&self, fp: *mut libc::FILE) -> (&'static [u8], c_int, usize, bool) {
-        let fp = fp as usize;
-        let (fd, eof) = self.fps[fp - self.base_fp];
+    fn file_data(&self, relpath: &str) -> Contents {
+        match self.node_at(relpath) {
+            Some(node) => self.node_data(node),
+            None => Contents::Borrowed(&[]),
+        }
+    }
+    // Without inflating anything
+    fn file_size(&self, relpath: &str) -> usize {
+        match self.node_at(relpath) {
+            Some(node) => self.node_size(node),
+            None => 0,
+        }
+    }
+
+    // What's at relpath now, links not followed
+    fn node_at(&self, relpath: &str) -> Option<Node> {
+        if let Some(&node) = self.overlay_files.get(relpath) {
+            return Some(Node::Overlay(node))
+        }
+        if self.is_dir(relpath) {
+            return Some(Node::Dir)
+        }
+        if self.whiteouts.contains(relpath) {
+            return None
+        }
+        FILES.get_key(relpath).map(|key| Node::Embedded(*key))
+    }
+    fn node_data(&self, node: Node) -> Contents {
+        match node {
+            Node::Embedded(key) => store::contents(&FILES[key]),
+            Node::Overlay(node) => Contents::Borrowed(&self.nodes[&node][..]),
+            Node::Dir => Contents::Borrowed(&[]),
+        }
+    }
+    fn node_size(&self, node: Node) -> usize {
+        match node {
+            Node::Embedded(key) => FILES[key].size,
+            Node::Overlay(node) => self.nodes[&node].len(),
+            Node::Dir => 0,
+        }
+    }
+    fn new_node(&mut self, data: Vec<u8>) -> usize {
+        let node = self.next_node;
+        self.next_node += 1;
+        self.nodes.insert(node, data);
+        node
+    }
+    // Drop the contents once neither a path nor an open description can
+    // reach them
+    fn release_node(&mut self, node: usize) {
+        if self.overlay_files.values().any(|&n| n == node) {
+            return
+        }
+        if self.descs.iter().any(|d| d.as_ref().map_or(false, |d| d.3 == Node::Overlay(node))) {
+            return
+        }
+        self.nodes.remove(&node);
+    }
+
+    // mtime, permission bits
+    fn meta(&self, relpath: &str) -> (i64, libc::mode_t) {
+        if let Some(&meta) = self.overlay_meta.get(relpath) {
+            return meta
+        }
+        if !self.whiteouts.contains(relpath) {
+            if let Some(entry) = FILES.get(relpath) {
+                return (entry.mtime, entry.mode)
+            }
+            if let Some(entry) = DIRS.get(relpath) {
+                return (entry.mtime, entry.mode)
+            }
+            if let Some(entry) = LINKS.get(relpath) {
+                return (entry.mtime, 0o777)
+            }
+        }
+        (now(), 0o644)
+    }
+    // Marks a file modified now
+    fn touch(&mut self, relpath: &str) {
+        let (_, mode) = self.meta(relpath);
+        self.overlay_meta.insert(String::from(relpath), (now(), mode));
+        self.dl_memfds.remove(relpath);
+    }
+
+    fn get_fp_data(&self, fp: *mut libc::FILE) -> (Contents, c_int, usize, bool) {
+        let (fd, eof, _) = self.fps[&(fp as usize)];
         let (data, offset) = self.get_fd_data(fd);
         (data, fd, offset, eof)
     }
     fn set_fp_offset(&mut self, fp: *mut libc::FILE, offset: usize) {
-        let fp = fp as usize;
-        let (fd, _) = self.fps[fp - self.base_fp];
+        let (fd, _, _) = self.fps[&(fp as usize)];
         self.set_fd_offset(fd, offset)
     }
     fn set_fp_eof(&mut self, fp: *mut libc::FILE, eof: bool) {
-        let fp = fp as usize;
-        let (fd, _) = self.fps[fp - self.base_fp];
-        self.fps[fp - self.base_fp] = (fd, eof)
+        self.fps.get_mut(&(fp as usize)).unwrap().1 = eof
+    }
+    fn is_fp_readable(&self, fp: *mut libc::FILE) -> bool {
+        self.fps[&(fp as usize)].2 != libc::O_WRONLY
+    }
+    fn is_fp_writable(&self, fp: *mut libc::FILE) -> bool {
+        self.fps[&(fp as usize)].2 != libc::O_RDONLY
     }
 
-    fn get_fd_path(&self, fd: c_int) -> &'static str {
-        let fd = fd as usize;
-        let (path, _) = self.fds[fd - self.base_fd];
-        path
+    fn get_fd_path(&self, fd: c_int) -> String {
+        self.desc(fd).0.clone()
     }
-    fn get_fd_data(&self, fd: c_int) -> (&'static [u8], usize) {
-        let fd = fd as usize;
-        let (path, offset) = self.fds[fd - self.base_fd];
-        (FILES.get(path).unwrap(), offset)
+    fn get_fd_abspath(&self, fd: c_int) -> PathBuf {
+        key_path(&self.get_fd_path(fd))
+    }
+    fn get_fd_data(&self, fd: c_int) -> (Contents, usize) {
+        let (_, offset, _, node) = *self.desc(fd);
+        (self.node_data(node), offset)
     }
     fn set_fd_offset(&mut self, fd: c_int, offset: usize) {
-        let fd = fd as usize;
-        let (path, _) = self.fds[fd - self.base_fd];
-        self.fds[fd - self.base_fd] = (path, offset)
+        self.desc_mut(fd).1 = offset
+    }
+    fn is_fd_writable(&self, fd: c_int) -> bool {
+        self.desc(fd).2 & O_ACCMODE != libc::O_RDONLY
+    }
+    fn desc(&self, fd: c_int) -> &(String, usize, c_int, Node) {
+        self.descs[self.fds[&fd]].as_ref().unwrap()
+    }
+    fn desc_mut(&mut self, fd: c_int) -> &mut (String, usize, c_int, Node) {
+        let desc = self.fds[&fd];
+        self.descs[desc].as_mut().unwrap()
+    }
+    fn alloc_desc(&mut self, desc: (String, usize, c_int, Node)) -> usize {
+        match self.descs.iter().position(|d| d.is_none()) {
+            Some(idx) => { self.descs[idx] = Some(desc); idx },
+            None => { self.descs.push(Some(desc)); self.descs.len() - 1 },
+        }
     }
 
-    fn open_as_fp(&mut self, fpath: &str) -> *mut libc::FILE {
-        let fd = self.open_as_fd(fpath);
-        self.fps.push((fd, false));
-        (self.base_fp + self.fps.len() - 1) as *mut libc::FILE
+    // newfd has already been made a duplicate of oldfd's placeholder
+    fn dup_fd(&mut self, oldfd: c_int, newfd: c_int) {
+        let desc = self.fds[&oldfd];
+        self.release_fd(newfd);
+        self.fds.insert(newfd, desc);
+        mark_fd(newfd, true);
+    }
+    // Forget fd, and its description if nothing else refers to it. The
+    // caller deals with the placeholder.
+    fn release_fd(&mut self, fd: c_int) {
+        if let Some(desc) = self.fds.remove(&fd) {
+            mark_fd(fd, false);
+            if !self.fds.values().any(|&d| d == desc) {
+                if let Some((_, _, _, Node::Overlay(node))) = self.descs[desc].take() {
+                    self.release_node(node)
+                }
+            }
+        }
+    }
+    fn release_fp(&mut self, fp: *mut libc::FILE) -> c_int {
+        let (fd, _, _) = self.fps.remove(&(fp as usize)).unwrap();
+        self.closed_handles.insert(fp as usize);
+        fd
+    }
+    fn release_dirp(&mut self, dirp: *mut libc::DIR) -> c_int {
+        let (fd, _, _, _) = self.dirps.remove(&(dirp as usize)).unwrap();
+        self.closed_handles.insert(dirp as usize);
+        fd
+    }
+    fn is_closed_handle(&self, handle: usize) -> bool {
+        self.closed_handles.contains(&handle)
+    }
+    fn forget_closed_handle(&mut self, handle: usize) {
+        self.closed_handles.remove(&handle);
+    }
+    // A memfd with the data that would be read next from fd, for numbers the
+    // kernel might read from directly, e.g. stdin of a child after exec
+    fn snapshot_fd(&self, fd: c_int) -> Option<c_int> {
+        let (ref path, offset, _, node) = *self.desc(fd);
+        if node == Node::Dir {
+            return None
+        }
+        let memfd = match sys::memfd_from_data(path, &self.node_data(node), 0) {
+            Ok(memfd) => memfd,
+            Err(_) => return None,
+        };
+        sys::lseek(memfd, offset as off_t, libc::SEEK_SET);
+        Some(memfd)
     }
-    fn open_as_fd(&mut self, fpath: &str) -> c_int {
-        let relpath = self.to_relpath(fpath);
-        let path = match FILES.get_key(relpath) {
-            Some(fpath) => fpath,
-            None => DIRS.get_key(relpath).unwrap(),
+
+    // Writes at offset, or at the fd offset (moving it on) if there isn't one
+    fn write_fd(&mut self, fd: c_int, buf: &[u8], offset: Option<usize>) -> Result<usize, c_int> {
+        if !self.is_fd_writable(fd) {
+            return Err(libc::EBADF)
+        }
+        let (path, cur_offset, flags, node) = self.desc(fd).clone();
+        let start = {
+            // Opening for writing always copies up
+            let data = match node {
+                Node::Overlay(node) => self.nodes.get_mut(&node).unwrap(),
+                _ => return Err(libc::EBADF),
+            };
+            let start = match offset {
+                Some(offset) => offset,
+                None if flags & libc::O_APPEND != 0 => data.len(),
+                None => cur_offset,
+            };
+            let end = start + buf.len();
+            if data.len() < end {
+                let extra = end - data.len();
+                data.extend(iter::repeat(0).take(extra));
+            }
+            unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), data[start..].as_mut_ptr(), buf.len()) };
+            start
         };
-        self.fds.push((path, 0));
-        (self.base_fd + self.fds.len() - 1) as c_int
+        if offset.is_none() {
+            self.set_fd_offset(fd, start + buf.len())
+        }
+        // Unless it's been unlinked or replaced since
+        if self.node_at(&path) == Some(node) {
+            self.touch(&path)
+        }
+        Ok(buf.len())
+    }
+    fn truncate(&mut self, fpath: &str, length: off_t) -> Result<(), c_int> {
+        let relpath = try!(self.lookup(fpath, true));
+        if self.is_dir(&relpath) {
+            return Err(libc::EISDIR)
+        }
+        if !self.is_file(&relpath) {
+            return Err(libc::ENOENT)
+        }
+        if length < 0 {
+            return Err(libc::EINVAL)
+        }
+        self.copy_up(&relpath);
+        let node = self.overlay_files[&relpath];
+        resize(self.nodes.get_mut(&node).unwrap(), length as usize);
+        self.touch(&relpath);
+        Ok(())
+    }
+    fn truncate_fd(&mut self, fd: c_int, length: off_t) -> Result<(), c_int> {
+        // As the kernel, an fd not open for writing is invalid here rather
+        // than bad
+        if length < 0 || !self.is_fd_writable(fd) {
+            return Err(libc::EINVAL)
+        }
+        let (path, _, _, node) = self.desc(fd).clone();
+        match node {
+            Node::Overlay(node) => resize(self.nodes.get_mut(&node).unwrap(), length as usize),
+            _ => return Err(libc::EINVAL),
+        }
+        // Unless it's been unlinked or replaced since
+        if self.node_at(&path) == Some(node) {
+            self.touch(&path)
+        }
+        Ok(())
+    }
+
+    // The data is copied into a private anonymous mapping, which the kernel's
+    // own munmap releases (in part or in whole) without any help
+    fn mmap_fd(&self, addr: *mut c_void, length: size_t, prot: c_int, flags: c_int,
+               fd: c_int, offset: off_t) -> Result<*mut c_void, c_int> {
+        let pagesize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as off_t;
+        if length == 0 || offset < 0 || offset % pagesize != 0 {
+            return Err(libc::EINVAL)
+        }
+        let (_, _, fdflags, node) = *self.desc(fd);
+        if node == Node::Dir {
+            return Err(libc::ENODEV)
+        }
+        if fdflags & O_ACCMODE == libc::O_WRONLY {
+            return Err(libc::EACCES)
+        }
+        // Changes could never make it back to the file
+        if flags & libc::MAP_SHARED != 0 && prot & libc::PROT_WRITE != 0 {
+            return Err(libc::EACCES)
+        }
+        let anon_flags = libc::MAP_PRIVATE | MAP_ANONYMOUS | (flags & libc::MAP_FIXED);
+        let rw = libc::PROT_READ | libc::PROT_WRITE;
+        let ptr = unsafe { __real_mmap(addr, length, rw, anon_flags, -1, 0) };
+        if ptr == MAP_FAILED {
+            return Err(get_errno())
+        }
+        unsafe { read_into_ptr(&self.node_data(node), ptr, offset as usize, length as usize) };
+        if prot != rw && unsafe { libc::mprotect(ptr, length, prot) } < 0 {
+            let errno = get_errno();
+            unsafe { libc::munmap(ptr, length) };
+            return Err(errno)
+        }
+        Ok(ptr)
+    }
+
+    fn open_as_fp(&mut self, fpath: &str, mode: &str) -> Result<*mut libc::FILE, c_int> {
+        let flags = try!(mode_to_flags(mode).ok_or(libc::EINVAL));
+        let fd = try!(self.open_as_fd(fpath, flags, 0o666));
+        self.fdopen_as_fp(fd, mode).map_err(|errno| {
+            self.release_fd(fd);
+            unsafe { __real_close(fd) };
+            errno
+        })
+    }
+    // The new fp refers to the same fd so shares its offset. As glibc, the
+    // mode can't ask for access the fd doesn't have, a puts the fd in append
+    // mode and e makes it close on exec.
+    fn fdopen_as_fp(&mut self, fd: c_int, mode: &str) -> Result<*mut libc::FILE, c_int> {
+        let flags = try!(mode_to_flags(mode).ok_or(libc::EINVAL));
+        let access = flags & O_ACCMODE;
+        let fd_access = self.desc(fd).2 & O_ACCMODE;
+        if fd_access != libc::O_RDWR && access != fd_access {
+            return Err(libc::EINVAL)
+        }
+        // The placeholder is open read-write, so any mode suits it, and libc
+        // sets close on exec on it itself
+        let cmode = try!(CString::new(mode).map_err(|_| libc::EINVAL));
+        let fp = unsafe { __real_fdopen(fd, cmode.as_ptr()) };
+        if fp.is_null() {
+            return Err(get_errno())
+        }
+        if flags & libc::O_APPEND != 0 {
+            self.desc_mut(fd).2 |= libc::O_APPEND
+        }
+        self.forget_closed_handle(fp as usize);
+        self.fps.insert(fp as usize, (fd, false, access));
+        Ok(fp)
+    }
+    fn opendir_as_dirp(&mut self, fpath: &str) -> Result<*mut libc::DIR, c_int> {
+        let fd = try!(self.open_as_fd(fpath, libc::O_RDONLY | O_DIRECTORY, 0));
+        self.fdopendir_as_dirp(fd).map_err(|errno| {
+            self.release_fd(fd);
+            unsafe { __real_close(fd) };
+            errno
+        })
+    }
+    // The new dirp owns fd, as with fdopendir
+    fn fdopendir_as_dirp(&mut self, fd: c_int) -> Result<*mut libc::DIR, c_int> {
+        let relpath = self.get_fd_path(fd);
+        if !self.is_dir(&relpath) {
+            return Err(libc::ENOTDIR)
+        }
+        let dirp = unsafe { __real_fdopendir(fd) };
+        if dirp.is_null() {
+            return Err(get_errno())
+        }
+        let de = Box::new(dirent {
+            d_ino: 0, d_off: 0, d_reclen: 0,
+            d_type: 0 as libc::c_uchar, d_name: [0; 256],
+        });
+        let entries = self.dir_entries(fd);
+        self.forget_closed_handle(dirp as usize);
+        self.dirps.insert(dirp as usize, (fd, entries, 0, de));
+        Ok(dirp)
+    }
+    // mode is only used if the file is created, less the umask
+    fn open_as_fd(&mut self, fpath: &str, flags: c_int, mode: libc::mode_t) -> Result<c_int, c_int> {
+        if flags & O_ACCMODE == O_ACCMODE {
+            return Err(libc::EINVAL)
+        }
+        let relpath = try!(self.lookup(fpath, true));
+        let isdir = self.is_dir(&relpath);
+        let exists = isdir || self.is_file(&relpath);
+        let write = flags & O_ACCMODE != libc::O_RDONLY;
+        let trunc = write && flags & libc::O_TRUNC != 0;
+        if !exists {
+            if flags & libc::O_CREAT == 0 {
+                return Err(libc::ENOENT)
+            }
+            self.whiteouts.remove(&relpath);
+            let node = self.new_node(vec![]);
+            self.overlay_files.insert(relpath.clone(), node);
+            let mode = mode & 0o7777 & !self.umask;
+            self.overlay_meta.insert(relpath.clone(), (now(), mode));
+        } else if flags & (libc::O_CREAT | libc::O_EXCL) == libc::O_CREAT | libc::O_EXCL {
+            return Err(libc::EEXIST)
+        } else if isdir && write {
+            return Err(libc::EISDIR)
+        } else if !isdir && flags & O_DIRECTORY != 0 {
+            return Err(libc::ENOTDIR)
+        }
+        let fd = try!(reserve_fd(isdir, flags));
+        if write {
+            self.copy_up(&relpath)
+        }
+        if trunc {
+            let node = self.overlay_files[&relpath];
+            self.nodes.get_mut(&node).unwrap().clear();
+            self.touch(&relpath)
+        }
+        let node = self.node_at(&relpath).unwrap();
+        let desc = self.alloc_desc((relpath, 0, flags, node));
+        self.fds.insert(fd, desc);
+        mark_fd(fd, true);
+        Ok(fd)
+    }
+
+    // Make sure a file has a mutable copy in the overlay, which anything
+    // open on the embedded file moves over to
+    fn copy_up(&mut self, relpath: &str) {
+        if self.overlay_files.contains_key(relpath) {
+            return
+        }
+        let embedded = self.node_at(relpath).unwrap();
+        let data = self.node_data(embedded).to_vec();
+        let node = self.new_node(data);
+        self.overlay_files.insert(String::from(relpath), node);
+        for desc in self.descs.iter_mut().filter_map(|d| d.as_mut()) {
+            if desc.3 == embedded {
+                desc.3 = Node::Overlay(node)
+            }
+        }
     }
 
-    fn get_inode(&mut self, fpath: &'static str) -> libc::ino_t {
+    fn unlink(&mut self, fpath: &str) -> Result<(), c_int> {
+        let relpath = try!(self.lookup(fpath, false));
+        if self.is_dir(&relpath) {
+            return Err(libc::EISDIR)
+        }
+        if !self.is_file(&relpath) && !self.is_link(&relpath) {
+            return Err(libc::ENOENT)
+        }
+        self.remove_file(&relpath);
+        Ok(())
+    }
+    fn mkdir(&mut self, fpath: &str, mode: libc::mode_t) -> Result<(), c_int> {
+        let relpath = try!(self.lookup(fpath, false));
+        if self.is_file(&relpath) || self.is_dir(&relpath) || self.is_link(&relpath) {
+            return Err(libc::EEXIST)
+        }
+        let mode = mode & 0o1777 & !self.umask;
+        self.make_dir(&relpath, mode);
+        Ok(())
+    }
+    fn rmdir(&mut self, fpath: &str) -> Result<(), c_int> {
+        let relpath = try!(self.lookup(fpath, false));
+        if !self.is_dir(&relpath) {
+            return Err(libc::ENOTDIR)
+        }
+        if is_mount_root(&relpath) {
+            return Err(libc::EBUSY)
+        }
+        if !self.list_dir(&relpath).is_empty() {
+            return Err(libc::ENOTEMPTY)
+        }
+        self.remove_dir(&relpath);
+        Ok(())
+    }
+    fn rename(&mut self, oldpath: &str, newpath: &str) -> Result<(), c_int> {
+        let oldrel = try!(self.lookup(oldpath, false));
+        let newrel = match self.lookup(newpath, false) {
+            Ok(newrel) => newrel,
+            Err(libc::ENOENT) => return Err(libc::EXDEV),
+            Err(errno) => return Err(errno),
+        };
+        if !self.is_file(&oldrel) && !self.is_dir(&oldrel) && !self.is_link(&oldrel) {
+            return Err(libc::ENOENT)
+        }
+        if mount_root(&oldrel) != mount_root(&newrel) {
+            return Err(libc::EXDEV)
+        }
+        self.rename_relpath(&oldrel, &newrel)
+    }
+    fn rename_relpath(&mut self, oldrel: &str, newrel: &str) -> Result<(), c_int> {
+        if oldrel == newrel {
+            return Ok(())
+        }
+        if self.is_dir(oldrel) {
+            if self.is_file(newrel) || self.is_link(newrel) {
+                return Err(libc::ENOTDIR)
+            }
+            if is_mount_root(oldrel) || Path::new(newrel).starts_with(oldrel) {
+                return Err(libc::EINVAL)
+            }
+            if self.is_dir(newrel) {
+                if !self.list_dir(newrel).is_empty() {
+                    return Err(libc::ENOTEMPTY)
+                }
+            } else {
+                let meta = self.meta(oldrel);
+                self.make_dir(newrel, meta.1);
+                self.overlay_meta.insert(String::from(newrel), meta);
+            }
+            for (name, _) in self.list_dir(oldrel) {
+                let oldchild = join_relpath(oldrel, &name);
+                let newchild = join_relpath(newrel, &name);
+                try!(self.rename_relpath(&oldchild, &newchild));
+            }
+            self.remove_dir(oldrel);
+        } else if self.is_link(oldrel) {
+            if self.is_dir(newrel) {
+                return Err(libc::EISDIR)
+            }
+            let target = self.link_target(oldrel).unwrap();
+            let meta = self.meta(oldrel);
+            self.remove_file(oldrel);
+            // Leaves any embedded entry at newrel whited out beneath the link
+            self.remove_file(newrel);
+            self.overlay_links.insert(String::from(newrel), target);
+            self.overlay_meta.insert(String::from(newrel), meta);
+        } else {
+            if self.is_dir(newrel) {
+                return Err(libc::EISDIR)
+            }
+            self.copy_up(oldrel);
+            let node = self.overlay_files.remove(oldrel).unwrap();
+            let meta = self.meta(oldrel);
+            self.remove_file(oldrel);
+            self.overlay_links.remove(newrel);
+            self.whiteouts.remove(newrel);
+            self.dl_memfds.remove(newrel);
+            if let Some(replaced) = self.overlay_files.insert(String::from(newrel), node) {
+                self.release_node(replaced)
+            }
+            self.overlay_meta.insert(String::from(newrel), meta);
+            // The file's open descriptions follow it to its new name
+            for desc in self.descs.iter_mut().filter_map(|d| d.as_mut()) {
+                if desc.3 == Node::Overlay(node) {
+                    desc.0 = String::from(newrel)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn make_dir(&mut self, relpath: &str, mode: libc::mode_t) {
+        self.whiteouts.remove(relpath);
+        self.overlay_dirs.insert(String::from(relpath));
+        self.overlay_meta.insert(String::from(relpath), (now(), mode));
+    }
+
+    // Permission bits are kept in overlay_meta, ownership isn't emulated
+    fn chmod(&mut self, fpath: &str, mode: libc::mode_t, follow: bool) -> Result<(), c_int> {
+        let relpath = try!(self.lookup(fpath, follow));
+        if self.is_link(&relpath) {
+            // As Linux, links have no mode of their own
+            return Err(EOPNOTSUPP)
+        }
+        if !self.is_file(&relpath) && !self.is_dir(&relpath) {
+            return Err(libc::ENOENT)
+        }
+        self.set_mode(&relpath, mode);
+        Ok(())
+    }
+    fn fchmod(&mut self, fd: c_int, mode: libc::mode_t) {
+        // Nothing to keep if the file's been unlinked or replaced since
+        if let Some(relpath) = self.fd_relpath(fd) {
+            self.set_mode(&relpath, mode)
+        }
+    }
+    fn set_mode(&mut self, relpath: &str, mode: libc::mode_t) {
+        let (mtime, _) = self.meta(relpath);
+        self.overlay_meta.insert(String::from(relpath), (mtime, mode & 0o7777));
+    }
+    // Where fd's file still is, None if it's since been unlinked or replaced
+    fn fd_relpath(&self, fd: c_int) -> Option<String> {
+        let (ref relpath, _, _, node) = *self.desc(fd);
+        let current = if node == Node::Dir { self.is_dir(relpath) } else { self.node_at(relpath) == Some(node) };
+        if current { Some(relpath.clone()) } else { None }
+    }
+
+    // Everything in the tree belongs to the caller, so the owner bits decide,
+    // except for root which may read and write anything, and execute anything
+    // with an x bit
+    fn access(&self, fpath: &str, mode: c_int, follow: bool) -> Result<(), c_int> {
+        let relpath = try!(self.lookup(fpath, follow));
+        if !self.is_file(&relpath) && !self.is_dir(&relpath) && !self.is_link(&relpath) {
+            return Err(libc::ENOENT)
+        }
+        self.access_relpath(&relpath, mode)
+    }
+    fn access_fd(&self, fd: c_int, mode: c_int) -> Result<(), c_int> {
+        let relpath = self.desc(fd).0.clone();
+        self.access_relpath(&relpath, mode)
+    }
+    fn access_relpath(&self, relpath: &str, mode: c_int) -> Result<(), c_int> {
+        if mode & !(R_OK | W_OK | X_OK) != 0 {
+            return Err(libc::EINVAL)
+        }
+        let perms = self.meta(relpath).1;
+        let wanted = mode as libc::mode_t;
+        let allowed = if unsafe { libc::getuid() } == 0 {
+            wanted & 0o1 == 0 || perms & 0o111 != 0
+        } else {
+            (perms >> 6) & wanted == wanted
+        };
+        if allowed { Ok(()) } else { Err(libc::EACCES) }
+    }
+    // Links included
+    fn remove_file(&mut self, relpath: &str) {
+        self.dl_memfds.remove(relpath);
+        if let Some(node) = self.overlay_files.remove(relpath) {
+            self.release_node(node)
+        }
+        self.overlay_links.remove(relpath);
+        self.overlay_meta.remove(relpath);
+        if FILES.contains_key(relpath) || LINKS.contains_key(relpath) {
+            self.whiteouts.insert(String::from(relpath));
+        }
+    }
+    fn remove_dir(&mut self, relpath: &str) {
+        self.overlay_dirs.remove(relpath);
+        self.overlay_meta.remove(relpath);
+        if DIRS.contains_key(relpath) {
+            self.whiteouts.insert(String::from(relpath));
+        }
+    }
+
+    // name, isdir
+    fn list_dir(&self, dpath_str: &str) -> Vec<(String, bool)> {
+        let dpath = Path::new(dpath_str);
+        let mut seen = HashSet::new();
+        let mut entries = vec![];
+        // The overlay only holds what's been written so is cheap to scan,
+        // embedded children come from the index generated by build.rs
+        let overlay = self.overlay_dirs.iter().map(|p| (&p[..], true))
+            .chain(self.overlay_files.keys().map(|p| (&p[..], false)))
+            .chain(self.overlay_links.keys().map(|p| (&p[..], false)));
+        let children: &[(&str, bool)] = DIRS.get(dpath_str).map_or(&[], |d| d.children);
+        let embedded = children.iter().cloned()
+            .filter(|&(p, _)| !self.whiteouts.contains(p));
+        for (subpath_str, isdir) in overlay.chain(embedded) {
+            let subpath = Path::new(subpath_str);
+            match subpath.parent() {
+                Some(parentpath) => if parentpath != dpath { continue },
+                None => continue,
+            }
+            // Overlay entries come first and shadow the embedded ones
+            if !seen.insert(subpath_str) {
+                continue
+            }
+            let name = subpath.file_name().unwrap().to_str().unwrap();
+            entries.push((String::from(name), isdir));
+        }
+        entries
+    }
+
+    fn get_inode(&mut self, fpath: &str) -> libc::ino_t {
         match self.inodes.get(fpath) {
-            Some(&inode) => inode,
-            None => {
-                let inode = (self.inodes.len() + self.base_inode) as libc::ino_t;
-                self.inodes.insert(fpath, inode);
-                inode
-            },
+            Some(&inode) => return inode,
+            None => (),
         }
+        let inode = (self.inodes.len() + self.base_inode) as libc::ino_t;
+        self.inodes.insert(String::from(fpath), inode);
+        inode
     }
 
     fn stat_fd(&mut self, fd: c_int) -> libc::stat {
-        let workdir = unsafe { WORKDIR };
-        let (path, _) = self.fds[fd as usize - self.base_fd];
-        self.stat(&format!("{}/{}", workdir, path))
-    }
-    // TODO: this method does lookups multiple times
-    fn stat(&mut self, fpath: &str) -> libc::stat {
-        let fpath = self.to_relpath(fpath);
-        let (isfile, isdir, fpath) = if FILES.contains_key(fpath) {
-            (true, false, FILES.get_key(fpath).unwrap())
-        } else if DIRS.contains(fpath) {
-            (false, true, DIRS.get_key(fpath).unwrap())
-        } else {
-            unreachable!()
+        let (relpath, _, _, node) = self.desc(fd).clone();
+        let mut stat = self.stat_relpath(&relpath);
+        // Unlinked while still open, or replaced by another file
+        if node != Node::Dir && self.node_at(&relpath) != Some(node) {
+            let len = self.node_size(node);
+            stat.st_mode = libc::S_IFREG | (stat.st_mode & 0o7777);
+            stat.st_nlink = 0;
+            stat.st_size = len as ssize_t;
+            stat.st_blocks = ((len + 1024) / 512) as ssize_t;
+        }
+        stat
+    }
+    fn stat(&mut self, fpath: &str) -> Result<libc::stat, c_int> {
+        let relpath = try!(self.lookup(fpath, true));
+        if !self.is_file(&relpath) && !self.is_dir(&relpath) {
+            return Err(libc::ENOENT)
+        }
+        Ok(self.stat_relpath(&relpath))
+    }
+    fn lstat(&mut self, fpath: &str) -> Result<libc::stat, c_int> {
+        let relpath = try!(self.lookup(fpath, false));
+        if !self.is_file(&relpath) && !self.is_dir(&relpath) && !self.is_link(&relpath) {
+            return Err(libc::ENOENT)
+        }
+        Ok(self.stat_relpath(&relpath))
+    }
+    fn readlink(&self, fpath: &str) -> Result<String, c_int> {
+        let relpath = try!(self.lookup(fpath, false));
+        match self.link_target(&relpath) {
+            Some(target) => Ok(target),
+            None if self.is_file(&relpath) || self.is_dir(&relpath) => Err(libc::EINVAL),
+            None => Err(libc::ENOENT),
+        }
+    }
+    // The absolute path with no ., .., repeated slashes or links in it
+    fn realpath(&self, fpath: &str) -> Result<String, c_int> {
+        let relpath = try!(self.lookup(fpath, true));
+        if !self.is_file(&relpath) && !self.is_dir(&relpath) {
+            return Err(libc::ENOENT)
+        }
+        // The workdir's own key is "", which would leave a trailing slash
+        Ok(String::from(key_path(&relpath).to_str().unwrap().trim_right_matches('/')))
+    }
+
+    // A path the kernel can exec in place of an embedded file, and the argv
+    // to exec it with. The file goes into a memfd, added to memfds for the
+    // caller to close once the exec is done or has failed, along with whether
+    // the new program needs it. #! scripts are run by their interpreter
+    // (embedded or not) as the kernel would, with the memfd's path standing
+    // in for the script's.
+    fn exec_target(&self, fpath: &str, argv: Vec<CString>, memfds: &mut Vec<(c_int, bool)>, depth: usize)
+            -> Result<(CString, Vec<CString>), c_int> {
+        let relpath = try!(self.lookup(fpath, true));
+        if self.is_dir(&relpath) {
+            return Err(libc::EACCES)
+        }
+        if !self.is_file(&relpath) {
+            return Err(libc::ENOENT)
+        }
+        if self.meta(&relpath).1 & 0o111 == 0 {
+            return Err(libc::EACCES)
+        }
+        let data = self.file_data(&relpath);
+        let script = data.starts_with(b"#!");
+        // The interpreter opens a script by path after the exec, so its fd
+        // has to be inherited. A binary is opened by the exec itself.
+        let memfd = try!(sys::memfd_from_data(&relpath, &data, sys::MFD_CLOEXEC));
+        memfds.push((memfd, script));
+        let procpath = CString::new(format!("/proc/self/fd/{}", memfd)).unwrap();
+        if !script {
+            return Ok((procpath, argv))
+        }
+        if depth >= MAXINTERP {
+            return Err(libc::ELOOP)
+        }
+        let (interp, arg) = match sys::parse_shebang(&data) {
+            Some(line) => line,
+            None => return Err(libc::ENOEXEC),
         };
+        let mut newargv = vec![try!(CString::new(interp).map_err(|_| libc::ENOEXEC))];
+        if !arg.is_empty() {
+            newargv.push(try!(CString::new(arg).map_err(|_| libc::ENOEXEC)));
+        }
+        newargv.push(procpath);
+        newargv.extend(argv.into_iter().skip(1));
+        match str::from_utf8(interp) {
+            Ok(interp) if self.exists(interp) => self.exec_target(interp, newargv, memfds, depth + 1),
+            _ => Ok((newargv[0].clone(), newargv)),
+        }
+    }
+    // A memfd the dynamic loader can load an embedded library from. Each
+    // library gets one, so loading it again finds the library already loaded
+    // rather than making a second copy. Writing, renaming over or unlinking
+    // the file forgets its memfd, so the next load sees the change. The memfd
+    // itself stays open for good, as the loader knows the library by its
+    // /proc/self/fd name and would mistake a reused number for it.
+    fn dl_memfd(&mut self, fpath: &str) -> Result<c_int, c_int> {
+        let relpath = try!(self.lookup(fpath, true));
+        if let Some(&memfd) = self.dl_memfds.get(&relpath) {
+            return Ok(memfd)
+        }
+        if !self.is_file(&relpath) {
+            return Err(libc::ENOENT)
+        }
+        let memfd = {
+            let data = self.file_data(&relpath);
+            try!(sys::memfd_from_data(&relpath, &data, sys::MFD_CLOEXEC))
+        };
+        self.dl_memfds.insert(relpath, memfd);
+        Ok(memfd)
+    }
+
+    fn stat_relpath(&mut self, relpath: &str) -> libc::stat {
+        let (mtime, mode) = self.meta(relpath);
         let mut stat = libc::stat {
             st_dev: 100000, // arbitrary
-            st_ino: self.get_inode(fpath),
-            st_mode: 0o100555, // normal file, read only
+            st_ino: self.get_inode(relpath),
+            st_mode: libc::S_IFREG | mode, // writable through the overlay
             st_nlink: 1,
-            st_uid: 1,
-            st_gid: 1,
+            st_uid: unsafe { libc::getuid() },
+            st_gid: unsafe { libc::getgid() },
             __pad0: 0, // ???
             st_rdev: 0, // arbitrary
             st_size: 0,
             st_blksize: 4096,
             st_blocks: 0,
-            st_atime: 0,
+            st_atime: mtime,
             st_atime_nsec: 0,
-            st_mtime: 0,
+            st_mtime: mtime,
             st_mtime_nsec: 0,
-            st_ctime: 0,
+            st_ctime: mtime,
             st_ctime_nsec: 0,
             __unused: [0, 0, 0],
         };
-        if isfile {
-            let data = FILES.get(fpath).unwrap();
-            stat.st_size = data.len() as ssize_t;
-            stat.st_blocks = ((data.len() + 1024) / 512) as ssize_t;
-        } else if isdir {
-            stat.st_mode = 0o040555; // directory, r+x
+        if self.is_file(relpath) {
+            let len = self.file_size(relpath);
+            stat.st_size = len as ssize_t;
+            stat.st_blocks = ((len + 1024) / 512) as ssize_t;
+        } else if self.is_dir(relpath) {
+            stat.st_mode = libc::S_IFDIR | mode;
             stat.st_nlink = 100;
             stat.st_size = 1024;
+        } else if let Some(target) = self.link_target(relpath) {
+            stat.st_mode = libc::S_IFLNK | mode;
+            stat.st_size = target.len() as ssize_t;
         } else {
-            unreachable!();
+            // Removed while still open
+            stat.st_nlink = 0;
         }
         stat
     }
 
+    // What a listing of the directory open on fd shows, . and .. included
+    fn dir_entries(&self, fd: c_int) -> Vec<(String, bool)> {
+        let dpath_str = self.get_fd_path(fd);
+        let mut entries = vec![(String::from("."), true), (String::from(".."), true)];
+        entries.extend(self.list_dir(&dpath_str));
+        entries
+    }
+    fn rewind_dirent(&mut self, dirp: *mut libc::DIR) {
+        let fd = self.dirps[&(dirp as usize)].0;
+        let entries = self.dir_entries(fd);
+        let dirstate = self.dirps.get_mut(&(dirp as usize)).unwrap();
+        dirstate.1 = entries;
+        dirstate.2 = 0
+    }
     fn seek_dirent(&mut self, dirp: *mut libc::DIR, loc: libc::c_long) {
-        let diro = dirp as usize - self.base_fp;
-        self.dirents[diro].0 = loc as usize
+        self.dirps.get_mut(&(dirp as usize)).unwrap().2 = loc as usize
     }
-    fn next_dirent(&mut self, dirp: *mut libc::DIR) -> *mut dirent {
-        let diro = dirp as usize - self.base_fp;
-        while self.dirents.len() <= diro {
-            self.dirents.push((0, dirent {
-                d_ino: 0, d_off: 0, d_reclen: 0,
-                d_type: 0 as libc::c_uchar, d_name: [0; 256],
-            }));
-        }
-
-        let (dirent_off, _) = self.dirents[diro];
-        let (fd, _) = self.fps[diro];
-        let dpath_str = self.get_fd_path(fd);
-        let dpath = Path::new(dpath_str);
-        let mut dirent_count = 0;
-        for &subpath_str in DIRS.iter().chain(FILES.keys()) {
-            let subpath = Path::new(subpath_str);
-            match subpath.parent() {
-                Some(parentpath) => if parentpath != dpath { continue },
-                None => continue,
-            }
-            if dirent_off != dirent_count {
-                dirent_count += 1;
-                continue
+    fn tell_dirent(&self, dirp: *mut libc::DIR) -> libc::c_long {
+        self.dirps[&(dirp as usize)].2 as libc::c_long
+    }
+    // Fills in the next entry of dirp, false at the end of the stream
+    fn read_dirent(&mut self, dirp: *mut libc::DIR, de: &mut dirent) -> bool {
+        let (name, isdir, dirent_off) = {
+            let (_, ref entries, dirent_off, _) = self.dirps[&(dirp as usize)];
+            if dirent_off >= entries.len() {
+                return false
             }
-            let fpath_str: &str = subpath.file_name().unwrap().to_str().unwrap();
-            let fpath_len = fpath_str.len();
-            assert!(fpath_len <= 256);
-            let mut de = dirent {
-                d_ino: self.get_inode(fpath_str),
-                d_off: dirent_off as c_long,
-                d_reclen: fpath_len as libc::c_ushort,
-                d_type: 0 as libc::c_uchar,
-                d_name: [0; 256],
-            };
-            let cbytes = CString::new(fpath_str).unwrap().as_ptr();
-            unsafe { ptr::copy(cbytes, de.d_name.as_mut_ptr(), fpath_len) };
-            self.dirents[diro] = (dirent_off + 1, de);
-            return (&mut self.dirents[diro].1) as *mut dirent
+            let (ref name, isdir) = entries[dirent_off];
+            (name.clone(), isdir, dirent_off)
+        };
+        let dpath_str = self.get_fd_path(self.dirps[&(dirp as usize)].0);
+        let relpath = match &name[..] {
+            "." => dpath_str,
+            // A mount root's parent is outside the tree, give it the root's
+            // inode
+            ".." if is_mount_root(&dpath_str) => dpath_str,
+            ".." => String::from(Path::new(&dpath_str).parent().unwrap().to_str().unwrap()),
+            _ => join_relpath(&dpath_str, &name),
+        };
+        let name_len = name.len();
+        assert!(name_len < 256);
+        *de = dirent {
+            d_ino: self.get_inode(&relpath),
+            // Linux convention, the offset of the next entry
+            d_off: (dirent_off + 1) as c_long,
+            d_reclen: mem::size_of::<dirent>() as libc::c_ushort,
+            d_type: if isdir { DT_DIR } else if self.is_link(&relpath) { DT_LNK } else { DT_REG },
+            d_name: [0; 256],
+        };
+        unsafe { ptr::copy(name.as_ptr() as *const c_char, de.d_name.as_mut_ptr(), name_len) };
+        self.dirps.get_mut(&(dirp as usize)).unwrap().2 = dirent_off + 1;
+        true
+    }
+    fn next_dirent(&mut self, dirp: *mut libc::DIR) -> *mut dirent {
+        let mut next: dirent = unsafe { mem::zeroed() };
+        if !self.read_dirent(dirp, &mut next) {
+            return ptr::null_mut()
         }
-        ptr::null_mut()
+        // Boxed so the pointer stays valid as other streams come and go
+        let de = &mut self.dirps.get_mut(&(dirp as usize)).unwrap().3;
+        **de = next;
+        &mut **de as *mut dirent
     }
 
     fn set_cwd(&mut self, dir: &str) {
-        self.cwd = Some(self.to_relpath(dir))
+        self.cwd = self.to_relpath(dir)
     }
     fn unset_cwd(&mut self) {
         self.cwd = None
     }
-    fn has_cwd(&self) -> bool {
-        self.cwd.is_some()
+    // The virtual cwd as an absolute path, None if the cwd is real
+    fn virtual_cwd_path(&self) -> Option<PathBuf> {
+        self.cwd.as_ref().map(|relpath| key_path(relpath))
     }
-    fn get_cwd(&self) -> Option<&'static str> {
-        self.cwd
-    }
-    fn actual_cwd_path(&self) -> PathBuf {
-        match self.cwd {
-            Some(pathstr) => PathBuf::from(pathstr),
-            None => {
-                // cannot use env::current_dir().unwrap() because deadlock
-                let cwd_ptr = unsafe { __real_getcwd(ptr::null_mut(), 0) };
-                let cwd_cstr = unsafe { CStr::from_ptr(cwd_ptr) };
-                let pb = PathBuf::from(cwd_cstr.to_str().unwrap());
-                unsafe { libc::free(cwd_ptr as *mut c_void) };
-                pb
-            }
+    // None if the real cwd can't be had (removed, unreadable, not utf-8), in
+    // which case nothing relative to it is in the tree
+    fn actual_cwd_path(&self) -> Option<PathBuf> {
+        if self.cwd.is_some() {
+            return self.virtual_cwd_path()
         }
+        // cannot use env::current_dir() because deadlock
+        let cwd_ptr = unsafe { __real_getcwd(ptr::null_mut(), 0) };
+        if cwd_ptr.is_null() {
+            return None
+        }
+        let pb = str::from_utf8(unsafe { CStr::from_ptr(cwd_ptr) }.to_bytes()).ok().map(PathBuf::from);
+        unsafe { libc::free(cwd_ptr as *mut c_void) };
+        pb
     }
 
-    fn to_relpath(&self, fpath: &str) -> &'static str {
-        let workdir = unsafe { WORKDIR };
-        let mut path = self.actual_cwd_path();
-        path.push(Path::new(fpath));
-        let relpath = path.relative_from(workdir).unwrap().to_str().unwrap();
-        let srelpath = FILES.get_key(relpath);
-        if srelpath.is_some() {
-            srelpath.unwrap()
-        } else {
-            DIRS.get_key(relpath).unwrap()
+    // Key for a path, whether or not anything exists there. Relative to
+    // the workdir, or absolute under the other mounts.
+    fn to_relpath(&self, fpath: &str) -> Option<String> {
+        self.lookup(fpath, true).ok()
+    }
+    // As to_relpath, following links in the tree (the last component's
+    // only if follow)
+    fn lookup(&self, fpath: &str, follow: bool) -> Result<String, c_int> {
+        let path = Path::new(fpath);
+        if path.is_absolute() {
+            return resolve_key(path, follow, |key| self.link_target(key))
+        }
+        match self.actual_cwd_path() {
+            Some(cwd) => resolve_key(&cwd.join(path), follow, |key| self.link_target(key)),
+            None => Err(libc::ENOENT),
         }
     }
 }
 
+// Cuts data short or pads it with zeros, as truncate does a file
+fn resize(data: &mut Vec<u8>, len: usize) {
+    if len <= data.len() {
+        data.truncate(len)
+    } else {
+        let extra = len - data.len();
+        data.extend(iter::repeat(0).take(extra))
+    }
+}
+
 unsafe fn read_into_ptr(src: &[u8], target_ptr: *mut c_void, offset: usize, count: usize) -> usize {
+    // Reading at or past the end is not an error, there's just nothing there
+    if offset >= src.len() {
+        return 0
+    }
     let src_ptr = (src.as_ptr() as usize + offset) as *const u8;
     let target_ptr = target_ptr as *mut u8;
 
@@ -286,27 +1169,176 @@ unsafe fn read_into_ptr(src: &[u8], target_ptr: *mut c_void, offset: usize, coun
 }
 
 lazy_static!{
-    static ref FILE_STATE: Arc<Mutex<FileState>> = Arc::new(Mutex::new(FileState {
-        cwd: None,
-        fds: vec![],
-        fps: vec![],
-        base_fp: 1,
-        base_fd: 100000,
-        dirents: vec![],
-        inodes: HashMap::new(),
-        base_inode: 0,
-    }));
+    static ref FILE_STATE: Arc<Mutex<FileState>> = Arc::new(Mutex::new(FileState::new()));
+}
+
+fn FS<'a>() -> MutexGuard<'a, FileState> {
+    FILE_STATE.lock().unwrap()
+}
+
+fn INIT() -> bool {
+    unsafe { IS_INITIALISED }
+}
+
+// Fds below this have a bit in VIRTUAL_FDS
+const FAST_FDS: usize = 65536;
+
+lazy_static!{
+    // A bit per fd number, set while it's virtual, so calls on real fds can
+    // skip the FS lock. After a fork in a threaded process the lock may be
+    // held by a thread that doesn't exist in the child, and the child's
+    // close and dup2 calls before exec mustn't wait for it.
+    static ref VIRTUAL_FDS: Vec<AtomicUsize> = (0..FAST_FDS / word_bits()).map(|_| AtomicUsize::new(0)).collect();
+}
+
+fn word_bits() -> usize {
+    mem::size_of::<usize>() * 8
+}
+fn mark_fd(fd: c_int, virt: bool) {
+    let fd = fd as usize;
+    if fd >= FAST_FDS {
+        return
+    }
+    let bit = 1 << (fd % word_bits());
+    if virt {
+        VIRTUAL_FDS[fd / word_bits()].fetch_or(bit, Ordering::SeqCst);
+    } else {
+        VIRTUAL_FDS[fd / word_bits()].fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+// False if fd certainly isn't virtual, without taking the lock
+fn maybe_virtual_fd(fd: c_int) -> bool {
+    if fd < 0 {
+        return false
+    }
+    let fd = fd as usize;
+    if fd >= FAST_FDS {
+        return true
+    }
+    VIRTUAL_FDS[fd / word_bits()].load(Ordering::SeqCst) & (1 << (fd % word_bits())) != 0
+}
+
+// Placeholders keep the reserved number in use and behave sensibly if they
+// do reach the kernel - reads give EOF, and directories are directories that
+// nothing can be found in. The lowest available number is picked, as open
+// would.
+fn reserve_fd(isdir: bool, flags: c_int) -> Result<c_int, c_int> {
+    if isdir {
+        return sys::dir_placeholder(flags)
+    }
+    let cloexec = flags & O_CLOEXEC;
+    let fd = unsafe { __real_open(b"/dev/null\0".as_ptr() as *const c_char, libc::O_RDWR | cloexec, 0) };
+    if fd < 0 { Err(get_errno()) } else { Ok(fd) }
+}
+
+// Embedded paths are all utf-8, so anything else goes to libc. A path in
+// the root that no mount can cover makes sure of that.
+unsafe fn path_from_ptr<'a>(path: *const c_char) -> &'a str {
+    str::from_utf8(CStr::from_ptr(path).to_bytes()).unwrap_or("/\u{fffd}")
+}
+
+// The absolute path a dirfd-relative call names, None if it's relative to a
+// real directory so can't be in the tree. An empty path names dirfd itself
+// if empty_ok, and nothing otherwise.
+unsafe fn at_path(dirfd: c_int, pathname: *const c_char, empty_ok: bool) -> Option<PathBuf> {
+    let str_path = path_from_ptr(pathname);
+    if str_path == "" && !empty_ok {
+        return None
+    }
+    let path = Path::new(str_path);
+    if path.is_absolute() {
+        return Some(PathBuf::from(path))
+    }
+    let fs = FS();
+    if dirfd == AT_FDCWD {
+        fs.actual_cwd_path().map(|cwd| cwd.join(path))
+    } else if fs.is_fd(dirfd) {
+        Some(fs.get_fd_abspath(dirfd).join(path))
+    } else {
+        None
+    }
+}
+
+// Whether a dirfd-relative call would act on something in the tree, or
+// create something there
+unsafe fn is_virtual_at(dirfd: c_int, pathname: *const c_char, empty_ok: bool) -> bool {
+    if *pathname == 0 && empty_ok && FS().is_fd(dirfd) {
+        return served(true)
+    }
+    match at_path(dirfd, pathname, empty_ok) {
+        Some(abs_path) => {
+            let abs_str_path = abs_path.to_str().unwrap();
+            let fs = FS();
+            served(fs.exists_nofollow(abs_str_path) || fs.can_create(abs_str_path))
+        },
+        None => false,
+    }
+}
+
+// Copies a path into a new malloc'd string, as getcwd and friends return
+unsafe fn malloc_path(path: &str) -> *mut c_char {
+    let buf = libc::malloc((path.len() + 1) as size_t) as *mut c_char;
+    if buf.is_null() {
+        set_errno(libc::ENOMEM);
+        return buf
+    }
+    ptr::copy_nonoverlapping(path.as_ptr(), buf as *mut u8, path.len());
+    *buf.offset(path.len() as isize) = 0;
+    buf
+}
+
+// Wrappers branch on these, which note for the trace when the call is being
+// served virtually rather than passed on to libc
+fn served(virt: bool) -> bool {
+    if virt {
+        trace::served_virtually()
+    }
+    virt
+}
+fn virtual_fd(fd: c_int) -> bool {
+    INIT() && maybe_virtual_fd(fd) && served(FS().is_fd(fd))
+}
+fn virtual_fp(fp: *mut libc::FILE) -> bool {
+    INIT() && served(FS().is_fp(fp))
+}
+fn virtual_dirp(dirp: *mut libc::DIR) -> bool {
+    INIT() && served(FS().is_dirp(dirp))
 }
 
-fn FS<'a>() -> MutexGuard<'a, FileState> {
-    FILE_STATE.lock().unwrap()
+// A FILE or DIR from libc may reuse the memory of one we closed
+fn forget_handle<T>(handle: *mut T) -> *mut T {
+    if INIT() && !handle.is_null() {
+        FS().forget_closed_handle(handle as usize)
+    }
+    handle
 }
 
-fn INIT() -> bool {
-    unsafe { IS_INITIALISED }
+// Turns an fopen mode string into open flags, None if fopen wouldn't
+// accept it
+fn mode_to_flags(mode: &str) -> Option<c_int> {
+    let mut chars = mode.chars();
+    let (access, flags) = match chars.next() {
+        Some('r') => (libc::O_RDONLY, 0),
+        Some('w') => (libc::O_WRONLY, libc::O_CREAT | libc::O_TRUNC),
+        Some('a') => (libc::O_WRONLY, libc::O_CREAT | libc::O_APPEND),
+        _ => return None,
+    };
+    // Remaining chars are modifiers, others (like b) don't matter here
+    let (mut access, mut flags) = (access, flags);
+    for c in chars {
+        match c {
+            '+' => access = libc::O_RDWR,
+            'e' => flags |= O_CLOEXEC,
+            'x' => flags |= libc::O_EXCL,
+            _ => (),
+        }
+    }
+    Some(access | flags)
 }
 
 extern {
+    static environ: *const *const c_char;
+
     fn __real_fclose(fp: *mut libc::FILE) -> c_int;
     fn __real_fopen(path: *const c_char, mode: *const c_char) -> *mut libc::FILE;
     fn __real_fopen64(path: *const c_char, mode: *const c_char) -> *mut libc::FILE;
@@ -314,10 +1346,16 @@ extern {
     fn __real_fdopen64(fd: c_int, mode: *const c_char) -> *mut libc::FILE;
     fn __real_freopen(path: *const c_char, mode: *const c_char, stream: *mut libc::FILE) -> *mut libc::FILE;
     fn __real_freopen64(path: *const c_char, mode: *const c_char, stream: *mut libc::FILE) -> *mut libc::FILE;
+    fn __real_popen(command: *const c_char, mode: *const c_char) -> *mut libc::FILE;
+    fn __real_tmpfile() -> *mut libc::FILE;
+    fn __real_tmpfile64() -> *mut libc::FILE;
+    fn __real_fmemopen(buf: *mut c_void, size: size_t, mode: *const c_char) -> *mut libc::FILE;
+    fn __real_open_memstream(ptr: *mut *mut c_char, sizeloc: *mut size_t) -> *mut libc::FILE;
     fn __real_fread(ptr: *mut c_void, size: size_t, nmemb: size_t, stream: *mut libc::FILE) -> size_t;
     fn __real_fread64(ptr: *mut c_void, size: size_t, nmemb: size_t, stream: *mut libc::FILE) -> size_t;
     fn __real_fwrite(ptr: *const c_void, size: size_t, nmemb: size_t, stream: *mut libc::FILE) -> size_t;
     fn __real_fwrite64(ptr: *const c_void, size: size_t, nmemb: size_t, stream: *mut libc::FILE) -> size_t;
+    fn __real_fflush(stream: *mut libc::FILE) -> c_int;
     fn __real_fgetc(stream: *mut libc::FILE) -> c_int;
     fn __real_fgets(s: *mut c_char, size: c_int, stream: *mut libc::FILE) -> *mut libc::c_char;
     fn __real_getc(stream: *mut libc::FILE) -> c_int;
@@ -357,10 +1395,12 @@ extern {
     fn __real_seekdir(dirp: *mut libc::DIR, loc: c_long);
     fn __real_telldir(dirp: *mut libc::DIR) -> c_long;
 
+    fn __real_close(fd: c_int) -> c_int;
     fn __real_dup(oldfd: c_int) -> c_int;
     fn __real_dup2(oldfd: c_int, newfd: c_int) -> c_int;
     fn __real_dup3(oldfd: c_int, newfd: c_int, flags: c_int) -> c_int;
     fn __real_read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t;
+    fn __real_write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t;
     fn __real_open(pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int;
     fn __real_open64(pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int;
     fn __real_openat(dirfd: c_int, pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int;
@@ -372,6 +1412,8 @@ extern {
     fn __real_pwrite(fd: c_int, buf: *const c_void, count: size_t, offset: off_t) -> ssize_t;
     fn __real_pwrite64(fd: c_int, buf: *const c_void, count: size_t, offset: off_t) -> ssize_t;
     fn __real_lseek(fd: c_int, offset: off_t, whence: c_int) -> off_t;
+    fn __real_mmap(addr: *mut c_void, length: size_t, prot: c_int, flags: c_int, fd: c_int, offset: off_t) -> *mut c_void;
+    fn __real_mmap64(addr: *mut c_void, length: size_t, prot: c_int, flags: c_int, fd: c_int, offset: off_t) -> *mut c_void;
     fn __real_lseek64(fd: c_int, offset: off_t, whence: c_int) -> off_t;
     fn __real_stat(path: *const c_char, buf: *mut libc::stat) -> c_int;
     fn __real_stat64(path: *const c_char, buf: *mut libc::stat) -> c_int;
@@ -386,647 +1428,1899 @@ extern {
     fn __real___fxstat(ver: c_int, fd: c_int, buf: *mut libc::stat) -> c_int;
     fn __real___fxstat64(ver: c_int, fd: c_int, buf: *mut libc::stat) -> c_int;
     fn __real_access(pathname: *const c_char, mode: c_int) -> c_int;
+    fn __real_faccessat(dirfd: c_int, pathname: *const c_char, mode: c_int, flags: c_int) -> c_int;
+    fn __real_fstatat(dirfd: c_int, pathname: *const c_char, buf: *mut libc::stat, flags: c_int) -> c_int;
+    fn __real___fxstatat(ver: c_int, dirfd: c_int, pathname: *const c_char, buf: *mut libc::stat, flags: c_int) -> c_int;
+    fn __real_statx(dirfd: c_int, pathname: *const c_char, flags: c_int, mask: c_uint, buf: *mut sys::statx) -> c_int;
+    fn __real_unlinkat(dirfd: c_int, pathname: *const c_char, flags: c_int) -> c_int;
+    fn __real_mkdirat(dirfd: c_int, pathname: *const c_char, mode: libc::mode_t) -> c_int;
+    fn __real_readlink(pathname: *const c_char, buf: *mut c_char, bufsiz: size_t) -> ssize_t;
+    fn __real_readlinkat(dirfd: c_int, pathname: *const c_char, buf: *mut c_char, bufsiz: size_t) -> ssize_t;
+    fn __real_unlink(pathname: *const c_char) -> c_int;
+    fn __real_rename(oldpath: *const c_char, newpath: *const c_char) -> c_int;
+    fn __real_renameat(olddirfd: c_int, oldpath: *const c_char, newdirfd: c_int, newpath: *const c_char) -> c_int;
+    fn __real_renameat2(olddirfd: c_int, oldpath: *const c_char, newdirfd: c_int, newpath: *const c_char, flags: c_uint) -> c_int;
+    fn __real_mkdir(pathname: *const c_char, mode: libc::mode_t) -> c_int;
+    fn __real_rmdir(pathname: *const c_char) -> c_int;
+    fn __real_truncate(path: *const c_char, length: off_t) -> c_int;
+    fn __real_ftruncate(fd: c_int, length: off_t) -> c_int;
+    fn __real_fsync(fd: c_int) -> c_int;
+    fn __real_fdatasync(fd: c_int) -> c_int;
+    fn __real_fchown(fd: c_int, owner: libc::uid_t, group: libc::gid_t) -> c_int;
+    fn __real_futimens(fd: c_int, times: *const libc::timespec) -> c_int;
+    fn __real_umask(mask: libc::mode_t) -> libc::mode_t;
+    fn __real_chmod(pathname: *const c_char, mode: libc::mode_t) -> c_int;
+    fn __real_fchmod(fd: c_int, mode: libc::mode_t) -> c_int;
+    fn __real_fchmodat(dirfd: c_int, pathname: *const c_char, mode: libc::mode_t, flags: c_int) -> c_int;
+    fn __real_fchownat(dirfd: c_int, pathname: *const c_char, owner: libc::uid_t, group: libc::gid_t, flags: c_int) -> c_int;
+    fn __real_utimensat(dirfd: c_int, pathname: *const c_char, times: *const libc::timespec, flags: c_int) -> c_int;
+    fn __real_linkat(olddirfd: c_int, oldpath: *const c_char, newdirfd: c_int, newpath: *const c_char, flags: c_int) -> c_int;
+    fn __real_symlinkat(target: *const c_char, newdirfd: c_int, linkpath: *const c_char) -> c_int;
+    fn __real_mknodat(dirfd: c_int, pathname: *const c_char, mode: libc::mode_t, dev: libc::dev_t) -> c_int;
+    fn __real___xmknodat(ver: c_int, dirfd: c_int, pathname: *const c_char, mode: libc::mode_t, dev: *mut libc::dev_t) -> c_int;
 
     fn __real_chdir(path: *const c_char) -> c_int;
     fn __real_fchdir(fd: c_int) -> c_int;
     fn __real_getcwd(buf: *mut c_char, size: size_t) -> *mut c_char;
     fn __real_getwd(buf: *mut c_char) -> *mut c_char;
     fn __real_get_current_dir_name() -> *mut c_char;
+    fn __real_realpath(path: *const c_char, resolved: *mut c_char) -> *mut c_char;
+    fn __real_canonicalize_file_name(path: *const c_char) -> *mut c_char;
+    fn __real_dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn __real_execve(path: *const c_char, argv: *const *const c_char, envp: *const *const c_char) -> c_int;
+    fn __real_fexecve(fd: c_int, argv: *const *const c_char, envp: *const *const c_char) -> c_int;
+    fn __real_posix_spawn(pid: *mut libc::pid_t, path: *const c_char, file_actions: *const c_void,
+                          attrp: *const c_void, argv: *const *const c_char, envp: *const *const c_char) -> c_int;
 }
 
 #[no_mangle]
 pub unsafe extern fn __wrap_fclose(fp: *mut libc::FILE) -> c_int {
-    if INIT() && FS().is_fp(fp) {
-        return 0
-    }
-    __real_fclose(fp)
+    traced!("fclose", [Fp(fp)], {
+        if INIT() {
+            let mut fs = FS();
+            if served(fs.is_fp(fp)) {
+                // Nothing is buffered in the FILE since the wrappers do all the
+                // io, so the real fclose just frees it and closes the placeholder
+                let fd = fs.release_fp(fp);
+                fs.release_fd(fd);
+                return __real_fclose(fp)
+            } else if served(fs.is_closed_handle(fp as usize)) {
+                set_errno(libc::EBADF);
+                return libc::EOF
+            }
+        }
+        __real_fclose(fp)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fopen(path: *const c_char, mode: *const c_char) -> *mut libc::FILE {
-    let str_path = str::from_utf8(CStr::from_ptr(path).to_bytes()).unwrap();
-    if INIT() && FS().exists(str_path) {
-        return FS().open_as_fp(str_path)
-    }
-    __real_fopen(path, mode)
+    traced!("fopen", [Path(path), Str(mode)], {
+        let str_path = path_from_ptr(path);
+        let str_mode = str::from_utf8(CStr::from_ptr(mode).to_bytes()).unwrap_or("");
+        if INIT() {
+            let mut fs = FS();
+            if served(fs.exists(str_path) || fs.can_create(str_path)) {
+                return match fs.open_as_fp(str_path, str_mode) {
+                    Ok(fp) => fp,
+                    Err(errno) => { set_errno(errno); ptr::null_mut() },
+                }
+            }
+        }
+        forget_handle(__real_fopen(path, mode))
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fopen64(path: *const c_char, mode: *const c_char) -> *mut libc::FILE {
-    __wrap_fopen(path, mode)
+    traced!("fopen64", [Path(path), Str(mode)], {
+        __wrap_fopen(path, mode)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fdopen(fd: c_int, mode: *const c_char) -> *mut libc::FILE {
-    if INIT() && FS().is_fd(fd) {
-        panic!("fdopen")
-    }
-    __real_fdopen(fd, mode)
+    traced!("fdopen", [Fd(fd), Str(mode)], {
+        if virtual_fd(fd) {
+            let str_mode = str::from_utf8(CStr::from_ptr(mode).to_bytes()).unwrap_or("");
+            return match FS().fdopen_as_fp(fd, str_mode) {
+                Ok(fp) => fp,
+                Err(errno) => { set_errno(errno); ptr::null_mut() },
+            }
+        }
+        forget_handle(__real_fdopen(fd, mode))
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fdopen64(fd: c_int, mode: *const c_char) -> *mut libc::FILE {
-    __wrap_fdopen(fd, mode)
+    traced!("fdopen64", [Fd(fd), Str(mode)], {
+        __wrap_fdopen(fd, mode)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_freopen(path: *const c_char, mode: *const c_char, stream: *mut libc::FILE) -> *mut libc::FILE {
-    let str_path = str::from_utf8(CStr::from_ptr(path).to_bytes()).unwrap();
-    if INIT() && (FS().exists(str_path) || FS().is_fp(stream)) {
-        panic!("freopen")
-    }
-    __real_freopen(path, mode, stream)
+    traced!("freopen", [Path(path), Str(mode), Fp(stream)], {
+        if INIT() {
+            let virt = FS().is_fp(stream) || (!path.is_null() && FS().exists(path_from_ptr(path)));
+            if served(virt) {
+                unsupported!("freopen", libc::ENOSYS, ptr::null_mut())
+            }
+        }
+        forget_handle(__real_freopen(path, mode, stream))
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_freopen64(path: *const c_char, mode: *const c_char, stream: *mut libc::FILE) -> *mut libc::FILE {
-    __wrap_freopen(path, mode, stream)
+    traced!("freopen64", [Path(path), Str(mode), Fp(stream)], {
+        __wrap_freopen(path, mode, stream)
+    })
+}
+// Other ways libc makes FILEs, only wrapped to watch for reused addresses
+#[no_mangle]
+pub unsafe extern fn __wrap_popen(command: *const c_char, mode: *const c_char) -> *mut libc::FILE {
+    traced!("popen", [Str(command), Str(mode)], {
+        forget_handle(__real_popen(command, mode))
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_tmpfile() -> *mut libc::FILE {
+    traced!("tmpfile", [], {
+        forget_handle(__real_tmpfile())
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_tmpfile64() -> *mut libc::FILE {
+    traced!("tmpfile64", [], {
+        forget_handle(__real_tmpfile64())
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_fmemopen(buf: *mut c_void, size: size_t, mode: *const c_char) -> *mut libc::FILE {
+    traced!("fmemopen", [Ptr(buf as *const c_void), Int(size as i64), Str(mode)], {
+        forget_handle(__real_fmemopen(buf, size, mode))
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_open_memstream(ptr: *mut *mut c_char, sizeloc: *mut size_t) -> *mut libc::FILE {
+    traced!("open_memstream", [Ptr(ptr as *const c_void), Ptr(sizeloc as *const c_void)], {
+        forget_handle(__real_open_memstream(ptr, sizeloc))
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fread(ptr: *mut c_void, size: size_t, nmemb: size_t, stream: *mut libc::FILE) -> size_t {
-    if INIT() && FS().is_fp(stream) {
-        // TODO: implement better
-        let (data, _, offset, _) = FS().get_fp_data(stream);
-        let lenleft = data.len() - offset;
-        let wanted = (size * nmemb) as usize;
-        let count = if lenleft > wanted { wanted } else { lenleft - (lenleft % size as usize) };
-        assert!(read_into_ptr(data, ptr, offset, count) == count);
-        FS().set_fp_offset(stream, offset + count);
-        return count as size_t / size;
-    }
-    __real_fread(ptr, size, nmemb, stream)
+    traced!("fread", [Ptr(ptr as *const c_void), Int(size as i64), Int(nmemb as i64), Fp(stream)], {
+        if virtual_fp(stream) {
+            if size == 0 || nmemb == 0 {
+                return 0
+            }
+            let mut fs = FS();
+            if !fs.is_fp_readable(stream) {
+                set_errno(libc::EBADF);
+                return 0
+            }
+            let (count, offset) = {
+                let (data, _, offset, _) = fs.get_fp_data(stream);
+                let lenleft = if offset < data.len() { data.len() - offset } else { 0 };
+                let wanted = (size as usize).saturating_mul(nmemb as usize);
+                // Whole items only
+                let count = if lenleft >= wanted { wanted } else { lenleft - (lenleft % size as usize) };
+                if read_into_ptr(&data, ptr, offset, count) != count {
+                    set_errno(libc::EIO);
+                    return 0
+                }
+                (count, offset)
+            };
+            fs.set_fp_offset(stream, offset + count);
+            if count < (size as usize).saturating_mul(nmemb as usize) {
+                fs.set_fp_eof(stream, true)
+            }
+            return count as size_t / size;
+        }
+        __real_fread(ptr, size, nmemb, stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fread64(ptr: *mut c_void, size: size_t, nmemb: size_t, stream: *mut libc::FILE) -> size_t {
-    __wrap_fread(ptr, size, nmemb, stream)
+    traced!("fread64", [Ptr(ptr as *const c_void), Int(size as i64), Int(nmemb as i64), Fp(stream)], {
+        __wrap_fread(ptr, size, nmemb, stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fwrite(ptr: *const c_void, size: size_t, nmemb: size_t, stream: *mut libc::FILE) -> size_t {
-    if INIT() && FS().is_fp(stream) {
-        panic!("fwrite")
-    }
-    __real_fwrite(ptr, size, nmemb, stream)
+    traced!("fwrite", [Ptr(ptr as *const c_void), Int(size as i64), Int(nmemb as i64), Fp(stream)], {
+        if virtual_fp(stream) {
+            if size == 0 || nmemb == 0 {
+                return 0
+            }
+            let len = match (size as usize).checked_mul(nmemb as usize) {
+                Some(len) => len,
+                None => { set_errno(libc::EINVAL); return 0 },
+            };
+            let mut fs = FS();
+            if !fs.is_fp_writable(stream) {
+                set_errno(libc::EBADF);
+                return 0
+            }
+            let (_, fd, _, _) = fs.get_fp_data(stream);
+            let buf = slice::from_raw_parts(ptr as *const u8, len);
+            return match fs.write_fd(fd, buf, None) {
+                Ok(count) => count as size_t / size,
+                Err(errno) => { set_errno(errno); 0 },
+            }
+        }
+        __real_fwrite(ptr, size, nmemb, stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fwrite64(ptr: *const c_void, size: size_t, nmemb: size_t, stream: *mut libc::FILE) -> size_t {
-    __wrap_fwrite(ptr, size, nmemb, stream)
+    traced!("fwrite64", [Ptr(ptr as *const c_void), Int(size as i64), Int(nmemb as i64), Fp(stream)], {
+        __wrap_fwrite(ptr, size, nmemb, stream)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_fflush(stream: *mut libc::FILE) -> c_int {
+    traced!("fflush", [Fp(stream)], {
+        // Virtual writes are unbuffered
+        if virtual_fp(stream) {
+            return 0
+        }
+        __real_fflush(stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fgetc(stream: *mut libc::FILE) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        panic!("fgetc")
-    }
-    __real_fgetc(stream)
+    traced!("fgetc", [Fp(stream)], {
+        if virtual_fp(stream) {
+            return __wrap_getc(stream)
+        }
+        __real_fgetc(stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fgets(s: *mut c_char, size: c_int, stream: *mut libc::FILE) -> *mut libc::c_char {
-    if INIT() && FS().is_fp(stream) {
-        let size = size as usize;
-        let (data, _, offset, _) = FS().get_fp_data(stream);
-        let size = if size + offset > data.len() { data.len() - offset } else { size };
-        let slice = &data[offset..offset+size];
-        let numtaken = match slice.position_elem(&('\n' as u8)) {
-            Some(numtaken) => numtaken + 1,
-            None => size,
-        };
-        ptr::copy(data[offset..].as_ptr() as *mut u8, s as *mut u8, numtaken);
-        let nul: &[u8] = &[0];
-        ptr::copy(nul.as_ptr(), (s as usize + numtaken) as *mut u8, 1);
-        let newoffset = offset + numtaken;
-        if newoffset == data.len() {
-            FS().set_fp_eof(stream, true)
+    traced!("fgets", [Ptr(s as *const c_void), Int(size as i64), Fp(stream)], {
+        if virtual_fp(stream) {
+            if size <= 0 {
+                set_errno(libc::EINVAL);
+                return ptr::null_mut()
+            }
+            let mut fs = FS();
+            if !fs.is_fp_readable(stream) {
+                set_errno(libc::EBADF);
+                return ptr::null_mut()
+            }
+            let (offset, numtaken, hit_end) = {
+                let (data, _, offset, _) = fs.get_fp_data(stream);
+                let left = if offset < data.len() { &data[offset..] } else { &[][..] };
+                // Room for the NUL is kept back
+                let max = cmp::min(size as usize - 1, left.len());
+                // hit_end if it stopped for want of data rather than room
+                let (numtaken, hit_end) = match left[..max].position_elem(&b'\n') {
+                    Some(pos) => (pos + 1, false),
+                    None => (max, max < size as usize - 1),
+                };
+                ptr::copy_nonoverlapping(left.as_ptr(), s as *mut u8, numtaken);
+                (offset, numtaken, hit_end)
+            };
+            if hit_end {
+                fs.set_fp_eof(stream, true)
+            }
+            if numtaken == 0 && hit_end {
+                return ptr::null_mut()
+            }
+            *s.offset(numtaken as isize) = 0;
+            fs.set_fp_offset(stream, offset + numtaken);
+            return s
         }
-        FS().set_fp_offset(stream, newoffset);
-        return if numtaken == 0 { ptr::null_mut() } else { s }
-    }
-    __real_fgets(s, size, stream)
+        __real_fgets(s, size, stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_getc(stream: *mut libc::FILE) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        let (data, _, offset, _) = FS().get_fp_data(stream);
-        if data.len() == offset {
-            return libc::EOF
+    traced!("getc", [Fp(stream)], {
+        if virtual_fp(stream) {
+            let mut fs = FS();
+            if !fs.is_fp_readable(stream) {
+                set_errno(libc::EBADF);
+                return libc::EOF
+            }
+            let (chr, offset) = {
+                let (data, _, offset, _) = fs.get_fp_data(stream);
+                if data.len() <= offset {
+                    return libc::EOF
+                }
+                (data[offset], offset)
+            };
+            fs.set_fp_offset(stream, offset + 1);
+            return chr as c_int
         }
-        let chr = data[offset];
-        FS().set_fp_offset(stream, offset + 1);
-        return chr as c_int
-    }
-    __real_getc(stream)
+        __real_getc(stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap__IO_getc(stream: *mut libc::FILE) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        let (data, _, offset, _) = FS().get_fp_data(stream);
-        if data.len() == offset {
-            return libc::EOF
+    traced!("_IO_getc", [Fp(stream)], {
+        if virtual_fp(stream) {
+            let mut fs = FS();
+            if !fs.is_fp_readable(stream) {
+                set_errno(libc::EBADF);
+                return libc::EOF
+            }
+            let (chr, offset) = {
+                let (data, _, offset, _) = fs.get_fp_data(stream);
+                if data.len() <= offset {
+                    return libc::EOF
+                }
+                (data[offset], offset)
+            };
+            fs.set_fp_offset(stream, offset + 1);
+            return chr as c_int
         }
-        let chr = data[offset];
-        FS().set_fp_offset(stream, offset + 1);
-        return chr as c_int
-    }
-    __real__IO_getc(stream)
+        __real__IO_getc(stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_ungetc(c: c_int, stream: *mut libc::FILE) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        let (data, _, offset, _) = FS().get_fp_data(stream);
-        assert!(data[offset - 1] == c as u8, "cannot unget mismatched char");
-        FS().set_fp_offset(stream, offset - 1);
-        return c
-    }
-    __real_ungetc(c, stream)
+    traced!("ungetc", [Int(c as i64), Fp(stream)], {
+        if virtual_fp(stream) {
+            let mut fs = FS();
+            let offset = {
+                let (data, _, offset, _) = fs.get_fp_data(stream);
+                if c == libc::EOF {
+                    return libc::EOF
+                }
+                // Only pushing back what was read can be served from the data
+                if offset == 0 || offset > data.len() || data[offset - 1] != c as u8 {
+                    unsupported!("ungetc", libc::EINVAL, libc::EOF)
+                }
+                offset
+            };
+            fs.set_fp_offset(stream, offset - 1);
+            return c
+        }
+        __real_ungetc(c, stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fseek(stream: *mut libc::FILE, offset: c_long, whence: c_int) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        let (data, _, cur_offset, _) = FS().get_fp_data(stream);
-        let seek_offset = match whence {
-            libc::SEEK_SET => offset,
-            libc::SEEK_CUR => cur_offset as off_t + offset,
-            libc::SEEK_END => data.len() as off_t + offset, // offset is signed!
-            w => offset + w as off_t,
-        } as usize;
-        FS().set_fp_offset(stream, seek_offset);
-        FS().set_fp_eof(stream, false);
-        return 0
-    }
-    __real_fseek(stream, offset, whence)
+    traced!("fseek", [Fp(stream), Int(offset as i64), Int(whence as i64)], {
+        if virtual_fp(stream) {
+            let mut fs = FS();
+            let seek_offset = {
+                let (data, _, cur_offset, _) = fs.get_fp_data(stream);
+                match whence {
+                    libc::SEEK_SET => offset,
+                    libc::SEEK_CUR => cur_offset as off_t + offset,
+                    libc::SEEK_END => data.len() as off_t + offset, // offset is signed!
+                    _ => { set_errno(libc::EINVAL); return -1 },
+                }
+            };
+            if seek_offset < 0 {
+                set_errno(libc::EINVAL);
+                return -1
+            }
+            fs.set_fp_offset(stream, seek_offset as usize);
+            fs.set_fp_eof(stream, false);
+            return 0
+        }
+        __real_fseek(stream, offset, whence)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fseek64(stream: *mut libc::FILE, offset: c_long, whence: c_int) -> c_int {
-    __wrap_fseek(stream, offset, whence)
+    traced!("fseek64", [Fp(stream), Int(offset as i64), Int(whence as i64)], {
+        __wrap_fseek(stream, offset, whence)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fseeko(stream: *mut libc::FILE, offset: off_t, whence: c_int) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        panic!("fseeko")
-    }
-    __real_fseeko(stream, offset, whence)
+    traced!("fseeko", [Fp(stream), Int(offset as i64), Int(whence as i64)], {
+        if virtual_fp(stream) {
+            return __wrap_fseek(stream, offset as c_long, whence)
+        }
+        __real_fseeko(stream, offset, whence)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fseeko64(stream: *mut libc::FILE, offset: off_t, whence: c_int) -> c_int {
-    __wrap_fseeko(stream, offset, whence)
+    traced!("fseeko64", [Fp(stream), Int(offset as i64), Int(whence as i64)], {
+        __wrap_fseeko(stream, offset, whence)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_ftell(stream: *mut libc::FILE) -> c_long {
-    if INIT() && FS().is_fp(stream) {
-        let (_, _, offset, _) = FS().get_fp_data(stream);
-        return offset as c_long
-    }
-    __real_ftell(stream)
+    traced!("ftell", [Fp(stream)], {
+        if virtual_fp(stream) {
+            let fs = FS();
+            let (_, _, offset, _) = fs.get_fp_data(stream);
+            return offset as c_long
+        }
+        __real_ftell(stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_ftell64(stream: *mut libc::FILE) -> c_long {
-    __wrap_ftell(stream)
+    traced!("ftell64", [Fp(stream)], {
+        __wrap_ftell(stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_ftello(stream: *mut libc::FILE) -> off_t {
-    if INIT() && FS().is_fp(stream) {
-        panic!("ftello")
-    }
-    __real_ftello(stream)
+    traced!("ftello", [Fp(stream)], {
+        if virtual_fp(stream) {
+            return __wrap_ftell(stream) as off_t
+        }
+        __real_ftello(stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_ftello64(stream: *mut libc::FILE) -> off_t {
-    __wrap_ftello(stream)
+    traced!("ftello64", [Fp(stream)], {
+        __wrap_ftello(stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_rewind(stream: *mut libc::FILE) {
-    if INIT() && FS().is_fp(stream) {
-        panic!("rewind")
-    }
-    __real_rewind(stream)
+    traced!("rewind", [Fp(stream)], {
+        if virtual_fp(stream) {
+            __wrap_fseek(stream, 0, libc::SEEK_SET);
+            __wrap_clearerr(stream);
+            return
+        }
+        __real_rewind(stream)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fgetpos(stream: *mut libc::FILE, pos: *mut fpos_t) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        panic!("fgetpos")
-    }
-    __real_fgetpos(stream, pos)
+    traced!("fgetpos", [Fp(stream), Ptr(pos as *const c_void)], {
+        // glibc's fpos_t starts with the offset, the conversion state after it
+        // is unused for byte streams
+        if virtual_fp(stream) {
+            *(pos as *mut off_t) = __wrap_ftell(stream) as off_t;
+            return 0
+        }
+        __real_fgetpos(stream, pos)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fgetpos64(stream: *mut libc::FILE, pos: *mut fpos_t) -> c_int {
-    __wrap_fgetpos(stream, pos)
+    traced!("fgetpos64", [Fp(stream), Ptr(pos as *const c_void)], {
+        __wrap_fgetpos(stream, pos)
+    })
 }
 #[no_mangle]
 pub unsafe extern fn __wrap_fsetpos(stream: *mut libc::FILE, pos: *mut fpos_t) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        panic!("fsetpos")
-    }
-    __real_fsetpos(stream, pos)
+    traced!("fsetpos", [Fp(stream), Ptr(pos as *const c_void)], {
+        if virtual_fp(stream) {
+            return __wrap_fseek(stream, *(pos as *mut off_t) as c_long, libc::SEEK_SET)
+        }
+        __real_fsetpos(stream, pos)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_fsetpos64(stream: *mut libc::FILE, pos: *mut fpos_t) -> c_int {
+    traced!("fsetpos64", [Fp(stream), Ptr(pos as *const c_void)], {
+        __wrap_fsetpos(stream, pos)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_clearerr(stream: *mut libc::FILE) {
+    traced!("clearerr", [Fp(stream)], {
+        if virtual_fp(stream) {
+            FS().set_fp_eof(stream, false);
+            return
+        }
+        __real_clearerr(stream)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_feof(stream: *mut libc::FILE) -> c_int {
+    traced!("feof", [Fp(stream)], {
+        if virtual_fp(stream) {
+            let fs = FS();
+            let (_, _, _, eof) = fs.get_fp_data(stream);
+            return if eof { 1 } else { 0 }
+        }
+        __real_feof(stream)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_ferror(stream: *mut libc::FILE) -> c_int {
+    traced!("ferror", [Fp(stream)], {
+        if virtual_fp(stream) {
+            return 0;
+        }
+        __real_ferror(stream)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_fileno(stream: *mut libc::FILE) -> c_int {
+    traced!("fileno", [Fp(stream)], {
+        if virtual_fp(stream) {
+            let fs = FS();
+            let (_, fd, _, _) = fs.get_fp_data(stream);
+            return fd
+        }
+        __real_fileno(stream)
+    })
+}
+// Virtual streams are backed by real FILEs, so their locks work as normal
+#[no_mangle]
+pub unsafe extern fn __wrap_flockfile(stream: *mut libc::FILE) {
+    traced!("flockfile", [Fp(stream)], {
+        __real_flockfile(stream)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_ftrylockfile(stream: *mut libc::FILE) -> c_int {
+    traced!("ftrylockfile", [Fp(stream)], {
+        __real_ftrylockfile(stream)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_funlockfile(stream: *mut libc::FILE) {
+    traced!("funlockfile", [Fp(stream)], {
+        __real_funlockfile(stream)
+    })
+}
+
+
+
+#[no_mangle]
+pub unsafe extern fn __wrap_opendir(name: *const c_char) -> *mut libc::DIR {
+    traced!("opendir", [Path(name)], {
+        let str_path = path_from_ptr(name);
+        if INIT() && served(FS().exists(str_path)) {
+            return match FS().opendir_as_dirp(str_path) {
+                Ok(dirp) => dirp,
+                Err(errno) => { set_errno(errno); ptr::null_mut() },
+            }
+        }
+        forget_handle(__real_opendir(name))
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_fdopendir(fd: c_int) -> *mut libc::DIR {
+    traced!("fdopendir", [Fd(fd)], {
+        if virtual_fd(fd) {
+            return match FS().fdopendir_as_dirp(fd) {
+                Ok(dirp) => dirp,
+                Err(errno) => { set_errno(errno); ptr::null_mut() },
+            }
+        }
+        forget_handle(__real_fdopendir(fd))
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_closedir(dirp: *mut libc::DIR) -> c_int {
+    traced!("closedir", [Dirp(dirp)], {
+        if INIT() {
+            let mut fs = FS();
+            if served(fs.is_dirp(dirp)) {
+                let fd = fs.release_dirp(dirp);
+                fs.release_fd(fd);
+                return __real_closedir(dirp)
+            } else if served(fs.is_closed_handle(dirp as usize)) {
+                set_errno(libc::EBADF);
+                return -1
+            }
+        }
+        __real_closedir(dirp)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_readdir(dirp: *mut libc::DIR) -> *mut libc::dirent_t {
+    traced!("readdir", [Dirp(dirp)], {
+        if virtual_dirp(dirp) {
+            return FS().next_dirent(dirp) as *mut libc::dirent_t
+        }
+        __real_readdir(dirp)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_readdir64(dirp: *mut libc::DIR) -> *mut libc::dirent_t {
+    traced!("readdir64", [Dirp(dirp)], {
+        __wrap_readdir(dirp)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_readdir_r(dirp: *mut libc::DIR, entry: *mut libc::DIR, result: *mut *mut libc::DIR) -> c_int {
+    traced!("readdir_r", [Dirp(dirp), Ptr(entry as *const c_void), Ptr(result as *const c_void)], {
+        if virtual_dirp(dirp) {
+            let found = FS().read_dirent(dirp, &mut *(entry as *mut dirent));
+            *result = if found { entry } else { ptr::null_mut() };
+            return 0
+        }
+        __real_readdir_r(dirp, entry, result)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_readdir_r64(dirp: *mut libc::DIR, entry: *mut libc::DIR, result: *mut *mut libc::DIR) -> c_int {
+    traced!("readdir_r64", [Dirp(dirp), Ptr(entry as *const c_void), Ptr(result as *const c_void)], {
+        __wrap_readdir_r(dirp, entry, result)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_rewinddir(dirp: *mut libc::DIR) {
+    traced!("rewinddir", [Dirp(dirp)], {
+        if virtual_dirp(dirp) {
+            FS().rewind_dirent(dirp);
+            return
+        }
+        __real_rewinddir(dirp)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_seekdir(dirp: *mut libc::DIR, loc: c_long) {
+    traced!("seekdir", [Dirp(dirp), Int(loc as i64)], {
+        if virtual_dirp(dirp) {
+            FS().seek_dirent(dirp, loc);
+            return
+        }
+        __real_seekdir(dirp, loc)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_telldir(dirp: *mut libc::DIR) -> c_long {
+    traced!("telldir", [Dirp(dirp)], {
+        if virtual_dirp(dirp) {
+            return FS().tell_dirent(dirp)
+        }
+        __real_telldir(dirp)
+    })
+}
+
+
+
+#[no_mangle]
+pub unsafe extern fn __wrap_close(fd: c_int) -> c_int {
+    traced!("close", [Fd(fd)], {
+        // Real fds don't wait for the lock, see VIRTUAL_FDS
+        if INIT() && maybe_virtual_fd(fd) {
+            // Closing the placeholder frees the number, so hold the lock until
+            // it's gone in case another thread is handed it by open
+            let mut fs = FS();
+            if served(fs.is_fd(fd)) {
+                fs.release_fd(fd);
+                return __real_close(fd)
+            }
+        }
+        __real_close(fd)
+    })
+}
+
+// Wrapped reads of newfd share oldfd's description, but the kernel is given
+// a snapshot of the contents (falling back to the placeholder) for anything
+// that reads the number directly
+unsafe fn dup_onto(fs: &mut FileState, oldfd: c_int, newfd: c_int, flags: c_int) -> c_int {
+    let srcfd = fs.snapshot_fd(oldfd).unwrap_or(oldfd);
+    let ret = __real_dup3(srcfd, newfd, flags);
+    if srcfd != oldfd {
+        let errno = get_errno();
+        sys::close(srcfd);
+        set_errno(errno);
+    }
+    if ret >= 0 {
+        fs.dup_fd(oldfd, newfd)
+    }
+    ret
+}
+
+#[no_mangle]
+pub unsafe extern fn __wrap_dup(oldfd: c_int) -> c_int {
+    traced!("dup", [Fd(oldfd)], {
+        if virtual_fd(oldfd) {
+            let mut fs = FS();
+            let newfd = __real_dup(oldfd);
+            if newfd >= 0 {
+                fs.dup_fd(oldfd, newfd)
+            }
+            return newfd
+        }
+        __real_dup(oldfd)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_dup2(oldfd: c_int, newfd: c_int) -> c_int {
+    traced!("dup2", [Fd(oldfd), Fd(newfd)], {
+        if virtual_fd(oldfd) {
+            if oldfd == newfd {
+                return newfd
+            }
+            return dup_onto(&mut FS(), oldfd, newfd, 0)
+        }
+        let ret = __real_dup2(oldfd, newfd);
+        if INIT() && ret >= 0 && maybe_virtual_fd(newfd) {
+            FS().release_fd(newfd)
+        }
+        ret
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_dup3(oldfd: c_int, newfd: c_int, flags: c_int) -> c_int {
+    traced!("dup3", [Fd(oldfd), Fd(newfd), Flags(flags)], {
+        if virtual_fd(oldfd) && oldfd != newfd {
+            return dup_onto(&mut FS(), oldfd, newfd, flags)
+        }
+        let ret = __real_dup3(oldfd, newfd, flags);
+        if INIT() && ret >= 0 && maybe_virtual_fd(newfd) {
+            FS().release_fd(newfd)
+        }
+        ret
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_open(pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+    traced!("open", [Path(pathname), Flags(flags), Mode(mode)], {
+        let str_path = path_from_ptr(pathname);
+        if INIT() {
+            let mut fs = FS();
+            if served(fs.exists(str_path) || (flags & libc::O_CREAT != 0 && fs.can_create(str_path))) {
+                return match fs.open_as_fd(str_path, flags, mode) {
+                    Ok(fd) => fd,
+                    Err(errno) => { set_errno(errno); -1 },
+                }
+            }
+        }
+        __real_open(pathname, flags, mode)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_open64(pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+    traced!("open64", [Path(pathname), Flags(flags), Mode(mode)], {
+        __wrap_open(pathname, flags, mode)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_openat(dirfd: c_int, pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+    traced!("openat", [Fd(dirfd), Path(pathname), Flags(flags), Mode(mode)], {
+        if INIT() {
+            if let Some(abs_path) = at_path(dirfd, pathname, false) {
+                // open passes it on to libc if it's not in the tree after all
+                let abs_str_path = abs_path.to_str().unwrap();
+                return __wrap_open(CString::new(abs_str_path).unwrap().as_ptr(), flags, mode)
+            }
+        }
+        __real_openat(dirfd, pathname, flags, mode)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_openat64(dirfd: c_int, pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+    traced!("openat64", [Fd(dirfd), Path(pathname), Flags(flags), Mode(mode)], {
+        __wrap_openat(dirfd, pathname, flags, mode)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_creat(pathname: *const c_char, mode: libc::mode_t) -> c_int {
+    traced!("creat", [Path(pathname), Mode(mode)], {
+        __wrap_open(pathname, libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC, mode)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_creat64(pathname: *const c_char, mode: libc::mode_t) -> c_int {
+    traced!("creat64", [Path(pathname), Mode(mode)], {
+        __wrap_creat(pathname, mode)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t {
+    traced!("read", [Fd(fd), Ptr(buf as *const c_void), Int(count as i64)], {
+        if virtual_fd(fd) {
+            let mut fs = FS();
+            let (actual_count, offset) = {
+                let (data, offset) = fs.get_fd_data(fd);
+                (read_into_ptr(&data, buf, offset, count as usize), offset)
+            };
+            fs.set_fd_offset(fd, offset + actual_count);
+            return actual_count as ssize_t
+        }
+        __real_read(fd, buf, count)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t {
+    traced!("write", [Fd(fd), Ptr(buf as *const c_void), Int(count as i64)], {
+        if virtual_fd(fd) {
+            let buf = slice::from_raw_parts(buf as *const u8, count as usize);
+            return match FS().write_fd(fd, buf, None) {
+                Ok(count) => count as ssize_t,
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real_write(fd, buf, count)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_pread(fd: c_int, buf: *mut c_void, count: size_t, offset: off_t) -> ssize_t {
+    traced!("pread", [Fd(fd), Ptr(buf as *const c_void), Int(count as i64), Int(offset as i64)], {
+        if virtual_fd(fd) {
+            if offset < 0 {
+                set_errno(libc::EINVAL);
+                return -1
+            }
+            // Unlike read, the fd offset is left untouched
+            let fs = FS();
+            let (data, _) = fs.get_fd_data(fd);
+            return read_into_ptr(&data, buf, offset as usize, count as usize) as ssize_t
+        }
+        __real_pread(fd, buf, count, offset)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_pread64(fd: c_int, buf: *mut c_void, count: size_t, offset: off_t) -> ssize_t {
+    traced!("pread64", [Fd(fd), Ptr(buf as *const c_void), Int(count as i64), Int(offset as i64)], {
+        __wrap_pread(fd, buf, count, offset)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_pwrite(fd: c_int, buf: *const c_void, count: size_t, offset: off_t) -> ssize_t {
+    traced!("pwrite", [Fd(fd), Ptr(buf as *const c_void), Int(count as i64), Int(offset as i64)], {
+        if virtual_fd(fd) {
+            if offset < 0 {
+                set_errno(libc::EINVAL);
+                return -1
+            }
+            let buf = slice::from_raw_parts(buf as *const u8, count as usize);
+            return match FS().write_fd(fd, buf, Some(offset as usize)) {
+                Ok(count) => count as ssize_t,
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real_pwrite(fd, buf, count, offset)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_pwrite64(fd: c_int, buf: *const c_void, count: size_t, offset: off_t) -> ssize_t {
+    traced!("pwrite64", [Fd(fd), Ptr(buf as *const c_void), Int(count as i64), Int(offset as i64)], {
+        __wrap_pwrite(fd, buf, count, offset)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_truncate(path: *const c_char, length: off_t) -> c_int {
+    traced!("truncate", [Path(path), Int(length as i64)], {
+        let str_path = path_from_ptr(path);
+        if INIT() && served(FS().exists(str_path)) {
+            return match FS().truncate(str_path, length) {
+                Ok(()) => 0,
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real_truncate(path, length)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_truncate64(path: *const c_char, length: off_t) -> c_int {
+    traced!("truncate64", [Path(path), Int(length as i64)], {
+        __wrap_truncate(path, length)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_ftruncate(fd: c_int, length: off_t) -> c_int {
+    traced!("ftruncate", [Fd(fd), Int(length as i64)], {
+        if virtual_fd(fd) {
+            return match FS().truncate_fd(fd, length) {
+                Ok(()) => 0,
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real_ftruncate(fd, length)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_ftruncate64(fd: c_int, length: off_t) -> c_int {
+    traced!("ftruncate64", [Fd(fd), Int(length as i64)], {
+        __wrap_ftruncate(fd, length)
+    })
+}
+// Virtual files only live in memory, so there's nothing to flush
+#[no_mangle]
+pub unsafe extern fn __wrap_fsync(fd: c_int) -> c_int {
+    traced!("fsync", [Fd(fd)], {
+        if virtual_fd(fd) {
+            return 0
+        }
+        __real_fsync(fd)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_fdatasync(fd: c_int) -> c_int {
+    traced!("fdatasync", [Fd(fd)], {
+        if virtual_fd(fd) {
+            return 0
+        }
+        __real_fdatasync(fd)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_lseek(fd: c_int, offset: off_t, whence: c_int) -> off_t {
+    traced!("lseek", [Fd(fd), Int(offset as i64), Int(whence as i64)], {
+        if virtual_fd(fd) {
+            let mut fs = FS();
+            let seek_offset = {
+                let (data, cur_offset) = fs.get_fd_data(fd);
+                match whence {
+                    libc::SEEK_SET => offset,
+                    libc::SEEK_CUR => cur_offset as off_t + offset,
+                    libc::SEEK_END => data.len() as off_t + offset, // offset is signed!
+                    _ => { set_errno(libc::EINVAL); return -1 },
+                }
+            };
+            if seek_offset < 0 {
+                set_errno(libc::EINVAL);
+                return -1
+            }
+            fs.set_fd_offset(fd, seek_offset as usize);
+            return seek_offset
+        }
+        __real_lseek(fd, offset, whence)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_fsetpos64(stream: *mut libc::FILE, pos: *mut fpos_t) -> c_int {
-    __wrap_fsetpos(stream, pos)
+pub unsafe extern fn __wrap_lseek64(fd: c_int, offset: off_t, whence: c_int) -> off_t {
+    traced!("lseek64", [Fd(fd), Int(offset as i64), Int(whence as i64)], {
+        __wrap_lseek(fd, offset, whence)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_clearerr(stream: *mut libc::FILE) {
-    if INIT() && FS().is_fp(stream) {
-        FS().set_fp_eof(stream, false);
-        return
-    }
-    __real_clearerr(stream)
+pub unsafe extern fn __wrap_mmap(addr: *mut c_void, length: size_t, prot: c_int, flags: c_int, fd: c_int, offset: off_t) -> *mut c_void {
+    traced!("mmap", [Ptr(addr as *const c_void), Int(length as i64), Flags(prot), Flags(flags), Fd(fd), Int(offset as i64)], {
+        if flags & MAP_ANONYMOUS == 0 && virtual_fd(fd) {
+            return match FS().mmap_fd(addr, length, prot, flags, fd, offset) {
+                Ok(ptr) => ptr,
+                Err(errno) => { set_errno(errno); MAP_FAILED },
+            }
+        }
+        __real_mmap(addr, length, prot, flags, fd, offset)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_feof(stream: *mut libc::FILE) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        let (_, _, _, eof) = FS().get_fp_data(stream);
-        return if eof { 1 } else { 0 }
-    }
-    __real_feof(stream)
+pub unsafe extern fn __wrap_mmap64(addr: *mut c_void, length: size_t, prot: c_int, flags: c_int, fd: c_int, offset: off_t) -> *mut c_void {
+    traced!("mmap64", [Ptr(addr as *const c_void), Int(length as i64), Flags(prot), Flags(flags), Fd(fd), Int(offset as i64)], {
+        __wrap_mmap(addr, length, prot, flags, fd, offset)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_ferror(stream: *mut libc::FILE) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        return 0;
-    }
-    __real_ferror(stream)
+pub unsafe extern fn __wrap_stat(path: *const c_char, buf: *mut libc::stat) -> c_int {
+    traced!("stat", [Path(path), Ptr(buf as *const c_void)], {
+        let str_path = path_from_ptr(path);
+        if INIT() && served(FS().exists(str_path)) {
+            return match FS().stat(str_path) {
+                Ok(stat) => { *buf = stat; 0 },
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real_stat(path, buf)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_fileno(stream: *mut libc::FILE) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        let (_, fd, _, _) = FS().get_fp_data(stream);
-        return fd
-    }
-    __real_fileno(stream)
+pub unsafe extern fn __wrap_stat64(path: *const c_char, buf: *mut libc::stat) -> c_int {
+    traced!("stat64", [Path(path), Ptr(buf as *const c_void)], {
+        __wrap_stat(path, buf)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_flockfile(stream: *mut libc::FILE) {
-    if INIT() && FS().is_fp(stream) {
-        panic!("flockfile");
-    }
-    __real_flockfile(stream)
+pub unsafe extern fn __wrap___xstat(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
+    traced!("__xstat", [Int(ver as i64), Path(path), Ptr(buf as *const c_void)], {
+        let str_path = path_from_ptr(path);
+        if INIT() && served(FS().exists(str_path)) {
+            return match FS().stat(str_path) {
+                Ok(stat) => { *buf = stat; 0 },
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real___xstat(ver, path, buf)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_ftrylockfile(stream: *mut libc::FILE) -> c_int {
-    if INIT() && FS().is_fp(stream) {
-        panic!("ftrylockfile");
-    }
-    __real_ftrylockfile(stream)
+pub unsafe extern fn __wrap___xstat64(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
+    traced!("__xstat64", [Int(ver as i64), Path(path), Ptr(buf as *const c_void)], {
+        __wrap___xstat(ver, path, buf)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_funlockfile(stream: *mut libc::FILE) {
-    if INIT() && FS().is_fp(stream) {
-        panic!("funlockfile");
-    }
-    __real_funlockfile(stream)
+pub unsafe extern fn __wrap_lstat(path: *const c_char, buf: *mut libc::stat) -> c_int {
+    traced!("lstat", [Path(path), Ptr(buf as *const c_void)], {
+        let str_path = path_from_ptr(path);
+        if INIT() && served(FS().exists_nofollow(str_path)) {
+            return match FS().lstat(str_path) {
+                Ok(stat) => { *buf = stat; 0 },
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real_lstat(path, buf)
+    })
 }
-
-
-
 #[no_mangle]
-pub unsafe extern fn __wrap_opendir(name: *const c_char) -> *mut libc::DIR {
-    let str_path = str::from_utf8(CStr::from_ptr(name).to_bytes()).unwrap();
-    let path = Path::new(str_path);
-    let cur_path = env::current_dir().unwrap();
-    let abs_path = cur_path.join(path);
-    let abs_path_str = abs_path.to_str().unwrap();
-    if INIT() && FS().exists(abs_path_str) {
-        let ret = FS().open_as_fp(abs_path_str) as *mut libc::DIR;
-        return ret
-    }
-    __real_opendir(name)
+pub unsafe extern fn __wrap_lstat64(path: *const c_char, buf: *mut libc::stat) -> c_int {
+    traced!("lstat64", [Path(path), Ptr(buf as *const c_void)], {
+        __wrap_lstat(path, buf)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_fdopendir(fd: c_int) -> *mut libc::DIR {
-    if INIT() && FS().is_fd(fd) {
-        panic!("fdopendir");
-    }
-    __real_fdopendir(fd)
+pub unsafe extern fn __wrap___lxstat(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
+    traced!("__lxstat", [Int(ver as i64), Path(path), Ptr(buf as *const c_void)], {
+        let str_path = path_from_ptr(path);
+        if INIT() && served(FS().exists_nofollow(str_path)) {
+            return match FS().lstat(str_path) {
+                Ok(stat) => { *buf = stat; 0 },
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real___lxstat(ver, path, buf)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_closedir(dirp: *mut libc::DIR) -> c_int {
-    let fp = dirp as *mut libc::FILE;
-    if INIT() && FS().is_fp(fp) {
-        __wrap_seekdir(dirp, 0);
-        return 0
-    }
-    __real_closedir(dirp)
+pub unsafe extern fn __wrap___lxstat64(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
+    traced!("__lxstat64", [Int(ver as i64), Path(path), Ptr(buf as *const c_void)], {
+        __wrap___lxstat(ver, path, buf)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_readlink(pathname: *const c_char, buf: *mut c_char, bufsiz: size_t) -> ssize_t {
+    traced!("readlink", [Path(pathname), Ptr(buf as *const c_void), Int(bufsiz as i64)], {
+        let str_path = path_from_ptr(pathname);
+        if INIT() && served(FS().exists_nofollow(str_path)) {
+            return match FS().readlink(str_path) {
+                Ok(target) => {
+                    // Silently truncated and not NUL terminated, as readlink(2)
+                    let len = cmp::min(target.len(), bufsiz as usize);
+                    ptr::copy_nonoverlapping(target.as_ptr() as *const c_char, buf, len);
+                    len as ssize_t
+                },
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real_readlink(pathname, buf, bufsiz)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_readdir(dirp: *mut libc::DIR) -> *mut libc::dirent_t {
-    let fp = dirp as *mut libc::FILE;
-    if INIT() && FS().is_fp(fp) {
-        return FS().next_dirent(dirp) as *mut libc::dirent_t
-    }
-    __real_readdir(dirp)
+pub unsafe extern fn __wrap_readlinkat(dirfd: c_int, pathname: *const c_char, buf: *mut c_char, bufsiz: size_t) -> ssize_t {
+    traced!("readlinkat", [Fd(dirfd), Path(pathname), Ptr(buf as *const c_void), Int(bufsiz as i64)], {
+        if INIT() {
+            if let Some(abs_path) = at_path(dirfd, pathname, false) {
+                let abs_str_path = abs_path.to_str().unwrap();
+                return __wrap_readlink(CString::new(abs_str_path).unwrap().as_ptr(), buf, bufsiz)
+            }
+        }
+        __real_readlinkat(dirfd, pathname, buf, bufsiz)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_readdir64(dirp: *mut libc::DIR) -> *mut libc::dirent_t {
-    __wrap_readdir(dirp)
+pub unsafe extern fn __wrap_fstat(fd: c_int, buf: *mut libc::stat) -> c_int {
+    traced!("fstat", [Fd(fd), Ptr(buf as *const c_void)], {
+        if virtual_fd(fd) {
+            *buf = FS().stat_fd(fd);
+            return 0
+        }
+        __real_fstat(fd, buf)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_readdir_r(dirp: *mut libc::DIR, entry: *mut libc::DIR, result: *mut *mut libc::DIR) -> c_int {
-    let fp = dirp as *mut libc::FILE;
-    if INIT() && FS().is_fp(fp) {
-        panic!("readdir_r");
-    }
-    __real_readdir_r(dirp, entry, result)
+pub unsafe extern fn __wrap_fstat64(fd: c_int, buf: *mut libc::stat) -> c_int {
+    traced!("fstat64", [Fd(fd), Ptr(buf as *const c_void)], {
+        __wrap_fstat(fd, buf)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_readdir_r64(dirp: *mut libc::DIR, entry: *mut libc::DIR, result: *mut *mut libc::DIR) -> c_int {
-    __wrap_readdir_r(dirp, entry, result)
+pub unsafe extern fn __wrap___fxstat(ver: c_int, fd: c_int, buf: *mut libc::stat) -> c_int {
+    traced!("__fxstat", [Int(ver as i64), Fd(fd), Ptr(buf as *const c_void)], {
+        if virtual_fd(fd) {
+            *buf = FS().stat_fd(fd);
+            return 0
+        }
+        __real___fxstat(ver, fd, buf)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_rewinddir(dirp: *mut libc::DIR) {
-    let fp = dirp as *mut libc::FILE;
-    if INIT() && FS().is_fp(fp) {
-        panic!("rewinddir");
-    }
-    __real_rewinddir(dirp)
+pub unsafe extern fn __wrap___fxstat64(ver: c_int, fd: c_int, buf: *mut libc::stat) -> c_int {
+    traced!("__fxstat64", [Int(ver as i64), Fd(fd), Ptr(buf as *const c_void)], {
+        __wrap___fxstat(ver, fd, buf)
+    })
 }
-#[no_mangle]
-pub unsafe extern fn __wrap_seekdir(dirp: *mut libc::DIR, loc: c_long) {
-    let fp = dirp as *mut libc::FILE;
-    if INIT() && FS().is_fp(fp) {
-        FS().seek_dirent(dirp, loc);
-        return
+
+// What a dirfd-relative stat finds, None if it's not in the tree
+unsafe fn stat_at(dirfd: c_int, pathname: *const c_char, flags: c_int) -> Option<Result<libc::stat, c_int>> {
+    if !INIT() {
+        return None
     }
-    __real_seekdir(dirp, loc)
-}
-#[no_mangle]
-pub unsafe extern fn __wrap_telldir(dirp: *mut libc::DIR) -> c_long {
-    let fp = dirp as *mut libc::FILE;
-    if INIT() && FS().is_fp(fp) {
-        panic!("telldir");
+    if *pathname == 0 && flags & AT_EMPTY_PATH != 0 && served(FS().is_fd(dirfd)) {
+        return Some(Ok(FS().stat_fd(dirfd)))
+    }
+    let abs_path = match at_path(dirfd, pathname, flags & AT_EMPTY_PATH != 0) {
+        Some(abs_path) => abs_path,
+        None => return None,
+    };
+    let abs_str_path = abs_path.to_str().unwrap();
+    let mut fs = FS();
+    if flags & AT_SYMLINK_NOFOLLOW != 0 {
+        if served(fs.exists_nofollow(abs_str_path)) {
+            return Some(fs.lstat(abs_str_path))
+        }
+    } else if served(fs.exists(abs_str_path)) {
+        return Some(fs.stat(abs_str_path))
+    }
+    None
+}
+fn statx_from_stat(stat: &libc::stat) -> sys::statx {
+    let timestamp = |sec: i64, nsec: i64| sys::statx_timestamp { tv_sec: sec, tv_nsec: nsec as u32, __reserved: 0 };
+    let dev = stat.st_dev as u64;
+    sys::statx {
+        stx_mask: STATX_BASIC_STATS,
+        stx_blksize: stat.st_blksize as u32,
+        stx_attributes: 0,
+        stx_nlink: stat.st_nlink as u32,
+        stx_uid: stat.st_uid,
+        stx_gid: stat.st_gid,
+        stx_mode: stat.st_mode as u16,
+        __spare0: [0],
+        stx_ino: stat.st_ino as u64,
+        stx_size: stat.st_size as u64,
+        stx_blocks: stat.st_blocks as u64,
+        stx_attributes_mask: 0,
+        stx_atime: timestamp(stat.st_atime as i64, stat.st_atime_nsec as i64),
+        // Not in STATX_BASIC_STATS, so not claimed in stx_mask
+        stx_btime: timestamp(0, 0),
+        stx_ctime: timestamp(stat.st_ctime as i64, stat.st_ctime_nsec as i64),
+        stx_mtime: timestamp(stat.st_mtime as i64, stat.st_mtime_nsec as i64),
+        stx_rdev_major: 0,
+        stx_rdev_minor: 0,
+        // As glibc's gnu_dev_major/gnu_dev_minor
+        stx_dev_major: (((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)) as u32,
+        stx_dev_minor: ((dev & 0xff) | ((dev >> 12) & !0xff)) as u32,
+        __spare2: [0; 14],
     }
-    __real_telldir(dirp)
 }
 
-
-
 #[no_mangle]
-pub unsafe extern fn __wrap_dup(oldfd: c_int) -> c_int {
-    if INIT() && FS().is_fd(oldfd) { panic!("dup") }
-    __real_dup(oldfd)
+pub unsafe extern fn __wrap_fstatat(dirfd: c_int, pathname: *const c_char, buf: *mut libc::stat, flags: c_int) -> c_int {
+    traced!("fstatat", [Fd(dirfd), Path(pathname), Ptr(buf as *const c_void), Flags(flags)], {
+        match stat_at(dirfd, pathname, flags) {
+            Some(Ok(stat)) => { *buf = stat; 0 },
+            Some(Err(errno)) => { set_errno(errno); -1 },
+            None => __real_fstatat(dirfd, pathname, buf, flags),
+        }
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_dup2(oldfd: c_int, newfd: c_int) -> c_int {
-    if INIT() && FS().is_fd(oldfd) || FS().is_fd(newfd) { panic!("dup2") }
-    __real_dup2(oldfd, newfd)
+pub unsafe extern fn __wrap_fstatat64(dirfd: c_int, pathname: *const c_char, buf: *mut libc::stat, flags: c_int) -> c_int {
+    traced!("fstatat64", [Fd(dirfd), Path(pathname), Ptr(buf as *const c_void), Flags(flags)], {
+        __wrap_fstatat(dirfd, pathname, buf, flags)
+    })
 }
+// The syscall's name, which some code calls directly
 #[no_mangle]
-pub unsafe extern fn __wrap_dup3(oldfd: c_int, newfd: c_int, flags: c_int) -> c_int {
-    if INIT() && FS().is_fd(oldfd) || FS().is_fd(newfd) { panic!("dup3") }
-    __real_dup3(oldfd, newfd, flags)
+pub unsafe extern fn __wrap_newfstatat(dirfd: c_int, pathname: *const c_char, buf: *mut libc::stat, flags: c_int) -> c_int {
+    traced!("newfstatat", [Fd(dirfd), Path(pathname), Ptr(buf as *const c_void), Flags(flags)], {
+        __wrap_fstatat(dirfd, pathname, buf, flags)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_open(pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
-    let str_path = str::from_utf8(CStr::from_ptr(pathname).to_bytes()).unwrap();
-    let path = Path::new(str_path);
-    let cur_path = env::current_dir().unwrap();
-    let abs_path = cur_path.join(path);
-    let abs_path_str = abs_path.to_str().unwrap();
-    if INIT() && FS().exists(abs_path_str) {
-        let allowed = 0 as c_int;
-        if flags | allowed != allowed {
-            panic!("open: invalid flag {}", flags);
+pub unsafe extern fn __wrap___fxstatat(ver: c_int, dirfd: c_int, pathname: *const c_char, buf: *mut libc::stat, flags: c_int) -> c_int {
+    traced!("__fxstatat", [Int(ver as i64), Fd(dirfd), Path(pathname), Ptr(buf as *const c_void), Flags(flags)], {
+        match stat_at(dirfd, pathname, flags) {
+            Some(Ok(stat)) => { *buf = stat; 0 },
+            Some(Err(errno)) => { set_errno(errno); -1 },
+            None => __real___fxstatat(ver, dirfd, pathname, buf, flags),
         }
-        if mode as c_int != libc::O_RDONLY {
-            panic!("open: invalid mode {}", mode);
-        }
-        // This violates the spec by not returning the lowest numbered
-        // unused fd
-        return FS().open_as_fd(str_path)
-    }
-    __real_open(pathname, flags, mode)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_open64(pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
-    __wrap_open(pathname, flags, mode)
+pub unsafe extern fn __wrap___fxstatat64(ver: c_int, dirfd: c_int, pathname: *const c_char, buf: *mut libc::stat, flags: c_int) -> c_int {
+    traced!("__fxstatat64", [Int(ver as i64), Fd(dirfd), Path(pathname), Ptr(buf as *const c_void), Flags(flags)], {
+        __wrap___fxstatat(ver, dirfd, pathname, buf, flags)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_openat(dirfd: c_int, pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
-    let str_path = str::from_utf8(CStr::from_ptr(pathname).to_bytes()).unwrap();
-    let path = Path::new(str_path);
-    let mut abs_path = PathBuf::new();
-    let isvirt = if !INIT() {
-        false
-    } else if path.is_absolute() {
-        abs_path.push(path);
-        FS().exists(str_path)
-    } else if dirfd != AT_FDCWD && !FS().is_fd(dirfd) {
-        false
-    } else {
-        let prefixpath = match dirfd {
-            AT_FDCWD => FS().actual_cwd_path(),
-            dfd => PathBuf::from(FS().get_fd_path(dfd)),
-        };
-        let path = prefixpath.join(path);
-        abs_path.push(&path);
-        FS().exists(path.to_str().unwrap())
-    };
-    if isvirt {
-        let abs_str_path = abs_path.to_str().unwrap();
-        return __wrap_open(CString::new(abs_str_path).unwrap().as_ptr(), flags, mode)
-    }
-    __real_openat(dirfd, pathname, flags, mode)
+pub unsafe extern fn __wrap_statx(dirfd: c_int, pathname: *const c_char, flags: c_int, mask: c_uint, buf: *mut sys::statx) -> c_int {
+    traced!("statx", [Fd(dirfd), Path(pathname), Flags(flags), Int(mask as i64), Ptr(buf as *const c_void)], {
+        // Everything in STATX_BASIC_STATS is filled in whatever the mask
+        match stat_at(dirfd, pathname, flags) {
+            Some(Ok(stat)) => { *buf = statx_from_stat(&stat); 0 },
+            Some(Err(errno)) => { set_errno(errno); -1 },
+            None => __real_statx(dirfd, pathname, flags, mask, buf),
+        }
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_openat64(dirfd: c_int, pathname: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
-    __wrap_openat(dirfd, pathname, flags, mode)
+pub unsafe extern fn __wrap_access(pathname: *const c_char, mode: c_int) -> c_int {
+    traced!("access", [Path(pathname), Int(mode as i64)], {
+        let str_path = path_from_ptr(pathname);
+        if INIT() && served(FS().exists(str_path)) {
+            return match FS().access(str_path, mode, true) {
+                Ok(()) => 0,
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real_access(pathname, mode)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_creat(pathname: *const c_char, mode: libc::mode_t) -> c_int {
-    let str_path = str::from_utf8(CStr::from_ptr(pathname).to_bytes()).unwrap();
-    let path = Path::new(str_path);
-    if path.starts_with(Path::new(WORKDIR)) {
-        panic!("creat");
-    }
-    __real_creat(pathname, mode)
-}
-pub unsafe extern fn __wrap_creat64(pathname: *const c_char, mode: libc::mode_t) -> c_int {
-    __wrap_creat(pathname, mode)
+pub unsafe extern fn __wrap_faccessat(dirfd: c_int, pathname: *const c_char, mode: c_int, flags: c_int) -> c_int {
+    traced!("faccessat", [Fd(dirfd), Path(pathname), Int(mode as i64), Flags(flags)], {
+        if *pathname == 0 && flags & AT_EMPTY_PATH != 0 && virtual_fd(dirfd) {
+            return match FS().access_fd(dirfd, mode) {
+                Ok(()) => 0,
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        if INIT() {
+            if let Some(abs_path) = at_path(dirfd, pathname, flags & AT_EMPTY_PATH != 0) {
+                let abs_str_path = abs_path.to_str().unwrap();
+                let fs = FS();
+                let follow = flags & AT_SYMLINK_NOFOLLOW == 0;
+                let exists = if follow { fs.exists(abs_str_path) } else { fs.exists_nofollow(abs_str_path) };
+                if served(exists) {
+                    return match fs.access(abs_str_path, mode, follow) {
+                        Ok(()) => 0,
+                        Err(errno) => { set_errno(errno); -1 },
+                    }
+                }
+            }
+        }
+        __real_faccessat(dirfd, pathname, mode, flags)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t {
-    if INIT() && FS().is_fd(fd) {
-        let (data, offset) = FS().get_fd_data(fd);
-        let actual_count = read_into_ptr(data, buf, offset, count as usize);
-        FS().set_fd_offset(fd, offset + actual_count);
-        return actual_count as ssize_t
-    }
-    __real_read(fd, buf, count)
+pub unsafe extern fn __wrap_unlink(pathname: *const c_char) -> c_int {
+    traced!("unlink", [Path(pathname)], {
+        let str_path = path_from_ptr(pathname);
+        if INIT() && served(FS().exists_nofollow(str_path)) {
+            return match FS().unlink(str_path) {
+                Ok(()) => 0,
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real_unlink(pathname)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_unlinkat(dirfd: c_int, pathname: *const c_char, flags: c_int) -> c_int {
+    traced!("unlinkat", [Fd(dirfd), Path(pathname), Flags(flags)], {
+        if INIT() {
+            if let Some(abs_path) = at_path(dirfd, pathname, false) {
+                let abs_cpath = CString::new(abs_path.to_str().unwrap()).unwrap();
+                if flags & AT_REMOVEDIR != 0 {
+                    return __wrap_rmdir(abs_cpath.as_ptr())
+                }
+                return __wrap_unlink(abs_cpath.as_ptr())
+            }
+        }
+        __real_unlinkat(dirfd, pathname, flags)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_rename(oldpath: *const c_char, newpath: *const c_char) -> c_int {
+    traced!("rename", [Path(oldpath), Path(newpath)], {
+        let str_oldpath = path_from_ptr(oldpath);
+        let str_newpath = path_from_ptr(newpath);
+        if INIT() {
+            let mut fs = FS();
+            let oldvirt = fs.exists_nofollow(str_oldpath);
+            let newvirt = fs.exists_nofollow(str_newpath) || fs.can_create(str_newpath);
+            if served(oldvirt && newvirt) {
+                return match fs.rename(str_oldpath, str_newpath) {
+                    Ok(()) => 0,
+                    Err(errno) => { set_errno(errno); -1 },
+                }
+            } else if served(oldvirt || newvirt) {
+                // Moving between the overlay and the real filesystem
+                set_errno(libc::EXDEV);
+                return -1
+            }
+        }
+        __real_rename(oldpath, newpath)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_renameat(olddirfd: c_int, oldpath: *const c_char, newdirfd: c_int, newpath: *const c_char) -> c_int {
+    traced!("renameat", [Fd(olddirfd), Path(oldpath), Fd(newdirfd), Path(newpath)], {
+        if INIT() {
+            match (at_path(olddirfd, oldpath, false), at_path(newdirfd, newpath, false)) {
+                (Some(old_abs_path), Some(new_abs_path)) => {
+                    let old_cpath = CString::new(old_abs_path.to_str().unwrap()).unwrap();
+                    let new_cpath = CString::new(new_abs_path.to_str().unwrap()).unwrap();
+                    return __wrap_rename(old_cpath.as_ptr(), new_cpath.as_ptr())
+                },
+                // One side is relative to a real directory, so can only be
+                // on the real filesystem
+                _ => if is_virtual_at(olddirfd, oldpath, false) || is_virtual_at(newdirfd, newpath, false) {
+                    set_errno(libc::EXDEV);
+                    return -1
+                },
+            }
+        }
+        __real_renameat(olddirfd, oldpath, newdirfd, newpath)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_pread(fd: c_int, buf: *mut c_void, count: size_t, offset: off_t) -> ssize_t {
-    if INIT() && FS().is_fd(fd) {
-        panic!("pread");
-    }
-    __real_pread(fd, buf, count, offset)
+pub unsafe extern fn __wrap_renameat2(olddirfd: c_int, oldpath: *const c_char, newdirfd: c_int, newpath: *const c_char, flags: c_uint) -> c_int {
+    traced!("renameat2", [Fd(olddirfd), Path(oldpath), Fd(newdirfd), Path(newpath), Int(flags as i64)], {
+        if INIT() && (is_virtual_at(olddirfd, oldpath, false) || is_virtual_at(newdirfd, newpath, false)) {
+            if flags & !RENAME_NOREPLACE != 0 {
+                unsupported!("renameat2", libc::EINVAL, -1)
+            }
+            if flags & RENAME_NOREPLACE != 0 {
+                if let Some(new_abs_path) = at_path(newdirfd, newpath, false) {
+                    if FS().exists_nofollow(new_abs_path.to_str().unwrap()) {
+                        set_errno(libc::EEXIST);
+                        return -1
+                    }
+                }
+            }
+            return __wrap_renameat(olddirfd, oldpath, newdirfd, newpath)
+        }
+        __real_renameat2(olddirfd, oldpath, newdirfd, newpath, flags)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_mkdir(pathname: *const c_char, mode: libc::mode_t) -> c_int {
+    traced!("mkdir", [Path(pathname), Mode(mode)], {
+        let str_path = path_from_ptr(pathname);
+        if INIT() {
+            let mut fs = FS();
+            if served(fs.exists_nofollow(str_path) || fs.can_create(str_path)) {
+                return match fs.mkdir(str_path, mode) {
+                    Ok(()) => 0,
+                    Err(errno) => { set_errno(errno); -1 },
+                }
+            }
+        }
+        __real_mkdir(pathname, mode)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_pread64(fd: c_int, buf: *mut c_void, count: size_t, offset: off_t) -> ssize_t {
-    __wrap_pread(fd, buf, count, offset)
+pub unsafe extern fn __wrap_mkdirat(dirfd: c_int, pathname: *const c_char, mode: libc::mode_t) -> c_int {
+    traced!("mkdirat", [Fd(dirfd), Path(pathname), Mode(mode)], {
+        if INIT() {
+            if let Some(abs_path) = at_path(dirfd, pathname, false) {
+                let abs_str_path = abs_path.to_str().unwrap();
+                return __wrap_mkdir(CString::new(abs_str_path).unwrap().as_ptr(), mode)
+            }
+        }
+        __real_mkdirat(dirfd, pathname, mode)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_pwrite(fd: c_int, buf: *const c_void, count: size_t, offset: off_t) -> ssize_t {
-    if INIT() && FS().is_fd(fd) {
-        panic!("pwrite");
-    }
-    __real_pwrite(fd, buf, count, offset)
+pub unsafe extern fn __wrap_rmdir(pathname: *const c_char) -> c_int {
+    traced!("rmdir", [Path(pathname)], {
+        let str_path = path_from_ptr(pathname);
+        if INIT() && served(FS().exists_nofollow(str_path)) {
+            return match FS().rmdir(str_path) {
+                Ok(()) => 0,
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real_rmdir(pathname)
+    })
 }
+
 #[no_mangle]
-pub unsafe extern fn __wrap_pwrite64(fd: c_int, buf: *const c_void, count: size_t, offset: off_t) -> ssize_t {
-    __wrap_pwrite(fd, buf, count, offset)
+pub unsafe extern fn __wrap_umask(mask: libc::mode_t) -> libc::mode_t {
+    traced!("umask", [Mode(mask)], {
+        let old = __real_umask(mask);
+        if INIT() {
+            FS().umask = mask & 0o777;
+        }
+        old
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_lseek(fd: c_int, offset: off_t, whence: c_int) -> off_t {
-    if INIT() && FS().is_fd(fd) {
-        let (data, cur_offset) = FS().get_fd_data(fd);
-        let seek_offset = match whence {
-            libc::SEEK_SET => offset,
-            libc::SEEK_CUR => cur_offset as off_t + offset,
-            libc::SEEK_END => data.len() as off_t + offset, // offset is signed!
-            w => offset + w as off_t,
-        } as usize;
-        FS().set_fd_offset(fd, seek_offset);
-        return 0
-    }
-    __real_lseek(fd, offset, whence)
+pub unsafe extern fn __wrap_chmod(pathname: *const c_char, mode: libc::mode_t) -> c_int {
+    traced!("chmod", [Path(pathname), Mode(mode)], {
+        let str_path = path_from_ptr(pathname);
+        if INIT() && served(FS().exists(str_path)) {
+            return match FS().chmod(str_path, mode, true) {
+                Ok(()) => 0,
+                Err(errno) => { set_errno(errno); -1 },
+            }
+        }
+        __real_chmod(pathname, mode)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_lseek64(fd: c_int, offset: off_t, whence: c_int) -> off_t {
-    __wrap_lseek(fd, offset, whence)
+pub unsafe extern fn __wrap_fchmod(fd: c_int, mode: libc::mode_t) -> c_int {
+    traced!("fchmod", [Fd(fd), Mode(mode)], {
+        if virtual_fd(fd) {
+            FS().fchmod(fd, mode);
+            return 0
+        }
+        __real_fchmod(fd, mode)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_stat(path: *const c_char, buf: *mut libc::stat) -> c_int {
-    let str_path = str::from_utf8(CStr::from_ptr(path).to_bytes()).unwrap();
-    if INIT() && FS().exists(str_path) {
-        *buf = FS().stat(str_path);
-        return 0
-    }
-    __real_stat(path, buf)
+pub unsafe extern fn __wrap_fchmodat(dirfd: c_int, pathname: *const c_char, mode: libc::mode_t, flags: c_int) -> c_int {
+    traced!("fchmodat", [Fd(dirfd), Path(pathname), Mode(mode), Flags(flags)], {
+        if *pathname == 0 && flags & AT_EMPTY_PATH != 0 && virtual_fd(dirfd) {
+            FS().fchmod(dirfd, mode);
+            return 0
+        }
+        if INIT() {
+            if let Some(abs_path) = at_path(dirfd, pathname, false) {
+                let abs_str_path = abs_path.to_str().unwrap();
+                let mut fs = FS();
+                let follow = flags & AT_SYMLINK_NOFOLLOW == 0;
+                let exists = if follow { fs.exists(abs_str_path) } else { fs.exists_nofollow(abs_str_path) };
+                if served(exists) {
+                    return match fs.chmod(abs_str_path, mode, follow) {
+                        Ok(()) => 0,
+                        Err(errno) => { set_errno(errno); -1 },
+                    }
+                }
+            }
+        }
+        __real_fchmodat(dirfd, pathname, mode, flags)
+    })
+}
+
+// Ownership and time changes, links and special files aren't emulated. They're refused
+// on anything in the tree rather than passed on, where they'd miss the file
+// or act on a placeholder.
+#[no_mangle]
+pub unsafe extern fn __wrap_fchownat(dirfd: c_int, pathname: *const c_char, owner: libc::uid_t, group: libc::gid_t, flags: c_int) -> c_int {
+    traced!("fchownat", [Fd(dirfd), Path(pathname), Int(owner as i64), Int(group as i64), Flags(flags)], {
+        if INIT() && is_virtual_at(dirfd, pathname, flags & AT_EMPTY_PATH != 0) {
+            unsupported!("fchownat", libc::EROFS, -1)
+        }
+        __real_fchownat(dirfd, pathname, owner, group, flags)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_stat64(path: *const c_char, buf: *mut libc::stat) -> c_int {
-    __wrap_stat(path, buf)
+pub unsafe extern fn __wrap_utimensat(dirfd: c_int, pathname: *const c_char, times: *const libc::timespec, flags: c_int) -> c_int {
+    traced!("utimensat", [Fd(dirfd), Path(pathname), Ptr(times as *const c_void), Flags(flags)], {
+        // A null pathname is futimens on dirfd
+        if pathname.is_null() && virtual_fd(dirfd) {
+            unsupported!("utimensat", libc::EROFS, -1)
+        }
+        if INIT() && !pathname.is_null() && is_virtual_at(dirfd, pathname, false) {
+            unsupported!("utimensat", libc::EROFS, -1)
+        }
+        __real_utimensat(dirfd, pathname, times, flags)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap___xstat(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
-    let str_path = str::from_utf8(CStr::from_ptr(path).to_bytes()).unwrap();
-    if INIT() && FS().exists(str_path) {
-        *buf = FS().stat(str_path);
-        return 0
-    }
-    __real___xstat(ver, path, buf)
+pub unsafe extern fn __wrap_fchown(fd: c_int, owner: libc::uid_t, group: libc::gid_t) -> c_int {
+    traced!("fchown", [Fd(fd), Int(owner as i64), Int(group as i64)], {
+        if virtual_fd(fd) {
+            unsupported!("fchown", libc::EROFS, -1)
+        }
+        __real_fchown(fd, owner, group)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap___xstat64(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
-    __wrap___xstat(ver, path, buf)
+pub unsafe extern fn __wrap_futimens(fd: c_int, times: *const libc::timespec) -> c_int {
+    traced!("futimens", [Fd(fd), Ptr(times as *const c_void)], {
+        if virtual_fd(fd) {
+            unsupported!("futimens", libc::EROFS, -1)
+        }
+        __real_futimens(fd, times)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_linkat(olddirfd: c_int, oldpath: *const c_char, newdirfd: c_int, newpath: *const c_char, flags: c_int) -> c_int {
+    traced!("linkat", [Fd(olddirfd), Path(oldpath), Fd(newdirfd), Path(newpath), Flags(flags)], {
+        if INIT() {
+            let oldvirt = is_virtual_at(olddirfd, oldpath, flags & AT_EMPTY_PATH != 0);
+            let newvirt = is_virtual_at(newdirfd, newpath, false);
+            if oldvirt && newvirt {
+                unsupported!("linkat", libc::EPERM, -1)
+            } else if oldvirt || newvirt {
+                set_errno(libc::EXDEV);
+                return -1
+            }
+        }
+        __real_linkat(olddirfd, oldpath, newdirfd, newpath, flags)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_lstat(path: *const c_char, buf: *mut libc::stat) -> c_int {
-    let str_path = str::from_utf8(CStr::from_ptr(path).to_bytes()).unwrap();
-    if INIT() && FS().exists(str_path) {
-        *buf = FS().stat(str_path);
-        return 0
-    }
-    __real_lstat(path, buf)
+pub unsafe extern fn __wrap_symlinkat(target: *const c_char, newdirfd: c_int, linkpath: *const c_char) -> c_int {
+    traced!("symlinkat", [Str(target), Fd(newdirfd), Path(linkpath)], {
+        if INIT() && is_virtual_at(newdirfd, linkpath, false) {
+            unsupported!("symlinkat", libc::EROFS, -1)
+        }
+        __real_symlinkat(target, newdirfd, linkpath)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_lstat64(path: *const c_char, buf: *mut libc::stat) -> c_int {
-    __wrap_lstat(path, buf)
+pub unsafe extern fn __wrap_mknodat(dirfd: c_int, pathname: *const c_char, mode: libc::mode_t, dev: libc::dev_t) -> c_int {
+    traced!("mknodat", [Fd(dirfd), Path(pathname), Mode(mode), Int(dev as i64)], {
+        if INIT() && is_virtual_at(dirfd, pathname, false) {
+            unsupported!("mknodat", libc::EROFS, -1)
+        }
+        __real_mknodat(dirfd, pathname, mode, dev)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap___lxstat(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
-    let str_path = str::from_utf8(CStr::from_ptr(path).to_bytes()).unwrap();
-    if INIT() && FS().exists(str_path) {
-        *buf = FS().stat(str_path);
-        return 0
-    }
-    __real___lxstat(ver, path, buf)
+pub unsafe extern fn __wrap___xmknodat(ver: c_int, dirfd: c_int, pathname: *const c_char, mode: libc::mode_t, dev: *mut libc::dev_t) -> c_int {
+    traced!("__xmknodat", [Int(ver as i64), Fd(dirfd), Path(pathname), Mode(mode), Ptr(dev as *const c_void)], {
+        if INIT() && is_virtual_at(dirfd, pathname, false) {
+            unsupported!("__xmknodat", libc::EROFS, -1)
+        }
+        __real___xmknodat(ver, dirfd, pathname, mode, dev)
+    })
 }
+
 #[no_mangle]
-pub unsafe extern fn __wrap___lxstat64(ver: c_int, path: *const c_char, buf: *mut libc::stat) -> c_int {
-    __wrap___lxstat(ver, path, buf)
+pub unsafe extern fn __wrap_chdir(path: *const c_char) -> c_int {
+    traced!("chdir", [Path(path)], {
+        let str_path = path_from_ptr(path);
+        if INIT() && served(FS().exists(str_path)) {
+            FS().set_cwd(str_path);
+            return 0
+        }
+        if INIT() { FS().unset_cwd(); }
+        __real_chdir(path)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_fstat(fd: c_int, buf: *mut libc::stat) -> c_int {
-    if INIT() && FS().is_fd(fd) {
-        *buf = FS().stat_fd(fd);
-        return 0
+pub unsafe extern fn __wrap_fchdir(fd: c_int) -> c_int {
+    traced!("fchdir", [Fd(fd)], {
+        if virtual_fd(fd) {
+            let mut fs = FS();
+            let abs_path = fs.get_fd_abspath(fd);
+            fs.set_cwd(abs_path.to_str().unwrap());
+            return 0
+        }
+        if INIT() { FS().unset_cwd(); }
+        __real_fchdir(fd)
+    })
+}
+// The virtual cwd, if there is one, for the getcwd family
+fn virtual_cwd() -> Option<PathBuf> {
+    if !INIT() {
+        return None
     }
-    __real_fstat(fd, buf)
+    let dir = FS().virtual_cwd_path();
+    served(dir.is_some());
+    dir
 }
 #[no_mangle]
-pub unsafe extern fn __wrap_fstat64(fd: c_int, buf: *mut libc::stat) -> c_int {
-    __wrap_fstat(fd, buf)
+pub unsafe extern fn __wrap_getcwd(buf: *mut c_char, size: size_t) -> *mut c_char {
+    traced!("getcwd", [Ptr(buf as *const c_void), Int(size as i64)], {
+        if let Some(dir) = virtual_cwd() {
+            let dir_str = dir.to_str().unwrap();
+            // As glibc, a null buf is allocated at the size asked for or, given
+            // 0, at the size needed
+            if buf.is_null() && size == 0 {
+                return malloc_path(dir_str)
+            }
+            if size == 0 {
+                set_errno(libc::EINVAL);
+                return ptr::null_mut()
+            }
+            if dir_str.len() >= size as usize {
+                set_errno(libc::ERANGE);
+                return ptr::null_mut()
+            }
+            let buf = if buf.is_null() { libc::malloc(size) as *mut c_char } else { buf };
+            if buf.is_null() {
+                set_errno(libc::ENOMEM);
+                return buf
+            }
+            ptr::copy_nonoverlapping(dir_str.as_ptr(), buf as *mut libc::c_uchar, dir_str.len());
+            *buf.offset(dir_str.len() as isize) = 0;
+            return buf
+        }
+        __real_getcwd(buf, size)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap___fxstat(ver: c_int, fd: c_int, buf: *mut libc::stat) -> c_int {
-    if INIT() && FS().is_fd(fd) {
-        *buf = FS().stat_fd(fd);
-        return 0
-    }
-    __real___fxstat(ver, fd, buf)
+pub unsafe extern fn __wrap_getwd(buf: *mut c_char) -> *mut c_char {
+    traced!("getwd", [Ptr(buf as *const c_void)], {
+        // buf is assumed to be PATH_MAX long
+        if let Some(dir) = virtual_cwd() {
+            let dir_str = dir.to_str().unwrap();
+            if dir_str.len() >= PATH_MAX {
+                set_errno(libc::ENAMETOOLONG);
+                return ptr::null_mut()
+            }
+            ptr::copy_nonoverlapping(dir_str.as_ptr(), buf as *mut libc::c_uchar, dir_str.len());
+            *buf.offset(dir_str.len() as isize) = 0;
+            return buf
+        }
+        __real_getwd(buf)
+    })
 }
 #[no_mangle]
-pub unsafe extern fn __wrap___fxstat64(ver: c_int, fd: c_int, buf: *mut libc::stat) -> c_int {
-    __wrap___fxstat(ver, fd, buf)
+pub unsafe extern fn __wrap_get_current_dir_name() -> *mut c_char {
+    traced!("get_current_dir_name", [], {
+        if let Some(dir) = virtual_cwd() {
+            return malloc_path(dir.to_str().unwrap())
+        }
+        __real_get_current_dir_name()
+    })
 }
+
 #[no_mangle]
-pub unsafe extern fn __wrap_access(pathname: *const c_char, mode: c_int) -> c_int {
-    let str_path = str::from_utf8(CStr::from_ptr(pathname).to_bytes()).unwrap();
-    if INIT() && FS().exists(str_path) {
-        if mode == libc::F_OK {
-            return 0
+pub unsafe extern fn __wrap_realpath(path: *const c_char, resolved: *mut c_char) -> *mut c_char {
+    traced!("realpath", [Path(path), Ptr(resolved as *const c_void)], {
+        if path.is_null() {
+            set_errno(libc::EINVAL);
+            return ptr::null_mut()
         }
-        if mode | libc::R_OK | libc::X_OK == libc::R_OK | libc::X_OK {
-            return 0
+        let str_path = path_from_ptr(path);
+        if INIT() && served(FS().exists(str_path)) {
+            let real = match FS().realpath(str_path) {
+                Ok(real) => real,
+                Err(errno) => { set_errno(errno); return ptr::null_mut() },
+            };
+            // resolved is assumed to be PATH_MAX long, null means allocate
+            if resolved.is_null() {
+                return malloc_path(&real)
+            }
+            if real.len() >= PATH_MAX {
+                set_errno(libc::ENAMETOOLONG);
+                return ptr::null_mut()
+            }
+            ptr::copy_nonoverlapping(real.as_ptr(), resolved as *mut libc::c_uchar, real.len());
+            *resolved.offset(real.len() as isize) = 0;
+            return resolved
         }
-        // deliberate fall-through to access denied
-    }
-    __real_access(pathname, mode)
+        __real_realpath(path, resolved)
+    })
 }
-
 #[no_mangle]
-pub unsafe extern fn __wrap_chdir(path: *const c_char) -> c_int {
-    let str_path = str::from_utf8(CStr::from_ptr(path).to_bytes()).unwrap();
-    if INIT() && FS().exists(str_path) {
-        FS().set_cwd(str_path);
-        return 0
+pub unsafe extern fn __wrap_canonicalize_file_name(path: *const c_char) -> *mut c_char {
+    traced!("canonicalize_file_name", [Path(path)], {
+        __wrap_realpath(path, ptr::null_mut())
+    })
+}
+
+// Copies a null terminated argv
+unsafe fn argv_to_vec(argv: *const *const c_char) -> Vec<CString> {
+    let mut args = vec![];
+    let mut i = 0;
+    while !(*argv.offset(i)).is_null() {
+        args.push(CString::new(CStr::from_ptr(*argv.offset(i)).to_bytes()).unwrap());
+        i += 1;
+    }
+    args
+}
+// Pointers to args, null terminated, valid as long as args is
+fn argv_ptrs(args: &[CString]) -> Vec<*const c_char> {
+    let mut ptrs: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+    ptrs.push(ptr::null());
+    ptrs
+}
+// Only now are the scripts' memfds made inheritable, so a fork elsewhere
+// in the meantime doesn't take them along
+fn inherit_memfds(memfds: &[(c_int, bool)]) {
+    for &(memfd, inherit) in memfds {
+        if inherit {
+            sys::clear_cloexec(memfd);
+        }
     }
-    if INIT() { FS().unset_cwd(); }
-    __real_chdir(path)
 }
-#[no_mangle]
-pub unsafe extern fn __wrap_fchdir(fd: c_int) -> c_int {
-    if INIT() && FS().is_fd(fd) {
-        let str_path = FS().get_fd_path(fd);
-        FS().set_cwd(str_path);
-        return 0
+fn close_memfds(memfds: Vec<(c_int, bool)>) {
+    let errno = get_errno();
+    for (memfd, _) in memfds {
+        sys::close(memfd);
     }
-    if INIT() { FS().unset_cwd(); }
-    __real_fchdir(fd)
+    set_errno(errno)
 }
+
 #[no_mangle]
-pub unsafe extern fn __wrap_getcwd(buf: *mut c_char, size: size_t) -> *mut c_char {
-    if INIT() && FS().has_cwd() {
-        if buf == ptr::null_mut() {
-            panic!("getcwd: null");
+pub unsafe extern fn __wrap_execve(path: *const c_char, argv: *const *const c_char, envp: *const *const c_char) -> c_int {
+    traced!("execve", [Path(path), Ptr(argv as *const c_void), Ptr(envp as *const c_void)], {
+        let str_path = path_from_ptr(path);
+        if INIT() && served(FS().exists(str_path)) {
+            let mut memfds = vec![];
+            let target = FS().exec_target(str_path, argv_to_vec(argv), &mut memfds, 0);
+            match target {
+                Ok((exe, args)) => {
+                    inherit_memfds(&memfds);
+                    __real_execve(exe.as_ptr(), argv_ptrs(&args).as_ptr(), envp);
+                },
+                Err(errno) => set_errno(errno),
+            }
+            // Only reached if the exec failed
+            close_memfds(memfds);
+            return -1
         }
-        let size = size as usize;
-        let reldir_str = FS().get_cwd().unwrap();
-        let mut dir = PathBuf::from(WORKDIR);
-        if reldir_str != "" {
-            dir.push(reldir_str);
+        __real_execve(path, argv, envp)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_execv(path: *const c_char, argv: *const *const c_char) -> c_int {
+    traced!("execv", [Path(path), Ptr(argv as *const c_void)], {
+        __wrap_execve(path, argv, environ)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_fexecve(fd: c_int, argv: *const *const c_char, envp: *const *const c_char) -> c_int {
+    traced!("fexecve", [Fd(fd), Ptr(argv as *const c_void), Ptr(envp as *const c_void)], {
+        if virtual_fd(fd) {
+            let abs_path = FS().get_fd_abspath(fd);
+            let abs_str_path = abs_path.to_str().unwrap();
+            return __wrap_execve(CString::new(abs_str_path).unwrap().as_ptr(), argv, envp)
         }
-        let dir_str = dir.to_str().unwrap();
-        if dir_str.len() > size {
-            panic!("getcwd: too big")
+        __real_fexecve(fd, argv, envp)
+    })
+}
+#[no_mangle]
+pub unsafe extern fn __wrap_posix_spawn(pid: *mut libc::pid_t, path: *const c_char, file_actions: *const c_void,
+                                        attrp: *const c_void, argv: *const *const c_char,
+                                        envp: *const *const c_char) -> c_int {
+    traced!("posix_spawn", [Path(path), Ptr(argv as *const c_void), Ptr(envp as *const c_void)], {
+        let str_path = path_from_ptr(path);
+        if INIT() && served(FS().exists(str_path)) {
+            let mut memfds = vec![];
+            let target = FS().exec_target(str_path, argv_to_vec(argv), &mut memfds, 0);
+            // Returns the error rather than setting errno. The child has the
+            // memfds by the time this returns, so they can go.
+            let ret = match target {
+                Ok((exe, args)) => {
+                    inherit_memfds(&memfds);
+                    __real_posix_spawn(pid, exe.as_ptr(), file_actions, attrp, argv_ptrs(&args).as_ptr(), envp)
+                },
+                Err(errno) => errno,
+            };
+            close_memfds(memfds);
+            return ret
         }
-        ptr::copy_nonoverlapping(dir_str.as_ptr(), buf as *mut libc::c_uchar, size);
-        return buf
-    }
-    __real_getcwd(buf, size)
+        __real_posix_spawn(pid, path, file_actions, attrp, argv, envp)
+    })
 }
+
 #[no_mangle]
-pub unsafe extern fn __wrap_getwd(buf: *mut c_char) -> *mut c_char {
-    if INIT() && FS().has_cwd() {
-        panic!("getcwd")
-    }
-    __real_getwd(buf)
+pub unsafe extern fn __wrap_dlopen(filename: *const c_char, flags: c_int) -> *mut c_void {
+    traced!("dlopen", [Path(filename), Flags(flags)], {
+        // Null is the program itself, and names without a slash are looked
+        // up by the loader in its own search path
+        if INIT() && !filename.is_null() {
+            let str_path = path_from_ptr(filename);
+            if str_path.contains('/') && FS().exists(str_path) {
+                // On failure the real dlopen fails too, leaving dlerror set
+                let memfd = FS().dl_memfd(str_path);
+                if let Ok(memfd) = memfd {
+                    trace::served_virtually();
+                    let procpath = CString::new(format!("/proc/self/fd/{}", memfd)).unwrap();
+                    return __real_dlopen(procpath.as_ptr(), flags)
+                }
+            }
+        }
+        __real_dlopen(filename, flags)
+    })
 }
-#[no_mangle]
-pub unsafe extern fn __wrap_get_current_dir_name() -> *mut c_char {
-    if INIT() && FS().has_cwd() {
-        panic!("get_current_dir_name")
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::path::Path;
+    use libc;
+    use libc::c_int;
+    use super::{FileState, Node, mode_to_flags, join_relpath, __real_close};
+    use super::super::{FILES, key_path, test_workdir};
+    use super::super::store;
+    use super::super::sys::{O_CLOEXEC, R_OK, W_OK, X_OK};
+
+    // An embedded file under the workdir, and the directory it's in
+    fn embedded_file() -> (&'static str, &'static str) {
+        let key = *FILES.keys().find(|key| !key.starts_with("/")).expect("no files in the payload");
+        (key, Path::new(key).parent().unwrap().to_str().unwrap())
+    }
+
+    // The path the application would open a key by
+    fn abs(key: &str) -> String {
+        test_workdir();
+        String::from(key_path(key).to_str().unwrap())
+    }
+
+    fn close(fs: &mut FileState, fd: c_int) {
+        fs.release_fd(fd);
+        unsafe { __real_close(fd) };
+    }
+
+    fn names(fs: &FileState, dir: &str) -> Vec<String> {
+        fs.list_dir(dir).into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn fopen_modes() {
+        assert_eq!(mode_to_flags("r"), Some(libc::O_RDONLY));
+        assert_eq!(mode_to_flags("rb"), Some(libc::O_RDONLY));
+        assert_eq!(mode_to_flags("r+"), Some(libc::O_RDWR));
+        assert_eq!(mode_to_flags("w"), Some(libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC));
+        assert_eq!(mode_to_flags("wb+"), Some(libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC));
+        assert_eq!(mode_to_flags("a"), Some(libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND));
+        assert_eq!(mode_to_flags("a+"), Some(libc::O_RDWR | libc::O_CREAT | libc::O_APPEND));
+        assert_eq!(mode_to_flags("re"), Some(libc::O_RDONLY | O_CLOEXEC));
+        assert_eq!(mode_to_flags("wx"), Some(libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_EXCL));
+        assert_eq!(mode_to_flags(""), None);
+        assert_eq!(mode_to_flags("+r"), None);
+        assert_eq!(mode_to_flags("x"), None);
+    }
+
+    #[test]
+    fn writes_copy_up_and_leave_the_payload_alone() {
+        let (key, _) = embedded_file();
+        let original = store::contents(&FILES[key]).to_vec();
+        let mut fs = FileState::new();
+        let fd = fs.open_as_fd(&abs(key), libc::O_RDWR, 0).unwrap();
+        assert!(fs.node_at(key) != Some(Node::Embedded(key)));
+        assert_eq!(fs.write_fd(fd, b"changed", Some(0)), Ok(7));
+        close(&mut fs, fd);
+        assert!(fs.file_data(key).starts_with(b"changed"));
+        assert_eq!(fs.file_size(key), cmp::max(original.len(), 7));
+        assert_eq!(fs.meta(key).1, FILES[key].mode);
+        assert_eq!(&store::contents(&FILES[key])[..], &original[..]);
+        assert_eq!(&FileState::new().file_data(key)[..], &original[..]);
+    }
+
+    #[test]
+    fn created_entries_take_the_mode_less_the_umask() {
+        let (_, dir) = embedded_file();
+        let key = join_relpath(dir, "pyinrs-test-new");
+        let dirkey = join_relpath(dir, "pyinrs-test-newdir");
+        let mut fs = FileState::new();
+        fs.umask = 0o027;
+        let fd = fs.open_as_fd(&abs(&key), libc::O_CREAT | libc::O_WRONLY, 0o777).unwrap();
+        close(&mut fs, fd);
+        assert!(fs.is_file(&key));
+        assert_eq!(fs.meta(&key).1, 0o750);
+        assert_eq!(fs.mkdir(&abs(&dirkey), 0o777), Ok(()));
+        assert_eq!(fs.meta(&dirkey).1, 0o750);
+        assert_eq!(fs.chmod(&abs(&key), 0o644, true), Ok(()));
+        assert_eq!(fs.meta(&key).1, 0o644);
+    }
+
+    #[test]
+    fn access_follows_the_recorded_mode() {
+        let (_, dir) = embedded_file();
+        let key = join_relpath(dir, "pyinrs-test-access");
+        let mut fs = FileState::new();
+        fs.umask = 0;
+        let fd = fs.open_as_fd(&abs(&key), libc::O_CREAT | libc::O_WRONLY, 0o600).unwrap();
+        close(&mut fs, fd);
+        assert_eq!(fs.access(&abs(&key), R_OK | W_OK, true), Ok(()));
+        // Even root needs an x bit
+        assert_eq!(fs.access(&abs(&key), X_OK, true), Err(libc::EACCES));
+        assert_eq!(fs.chmod(&abs(&key), 0o700, true), Ok(()));
+        assert_eq!(fs.access(&abs(&key), X_OK, true), Ok(()));
+        assert_eq!(fs.access(&abs(&key), 0o10, true), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn unlink_whites_out_embedded_files() {
+        let (key, dir) = embedded_file();
+        let name = Path::new(key).file_name().unwrap().to_str().unwrap();
+        let mut fs = FileState::new();
+        assert!(names(&fs, dir).iter().any(|n| n == name));
+        assert_eq!(fs.unlink(&abs(key)), Ok(()));
+        assert!(!fs.is_file(key));
+        assert!(fs.whiteouts.contains(key));
+        assert!(!names(&fs, dir).iter().any(|n| n == name));
+        assert_eq!(fs.unlink(&abs(key)), Err(libc::ENOENT));
+        // Creating it again gives an empty file, not the embedded one
+        let fd = fs.open_as_fd(&abs(key), libc::O_CREAT | libc::O_WRONLY, 0o644).unwrap();
+        close(&mut fs, fd);
+        assert!(fs.is_file(key));
+        assert_eq!(fs.file_size(key), 0);
+        assert_eq!(names(&fs, dir).iter().filter(|n| *n == name).count(), 1);
+    }
+
+    #[test]
+    fn rename_moves_embedded_files_into_the_overlay() {
+        let (key, dir) = embedded_file();
+        let newkey = join_relpath(dir, "pyinrs-test-renamed");
+        let original = store::contents(&FILES[key]).to_vec();
+        let mut fs = FileState::new();
+        assert_eq!(fs.rename(&abs(key), &abs(&newkey)), Ok(()));
+        assert!(!fs.is_file(key));
+        assert!(fs.whiteouts.contains(key));
+        assert_eq!(&fs.file_data(&newkey)[..], &original[..]);
+        assert_eq!(fs.meta(&newkey).1, FILES[key].mode);
+        assert_eq!(fs.rename(&abs(key), &abs(&newkey)), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn listings_show_shadowed_entries_once() {
+        let (key, dir) = embedded_file();
+        let name = Path::new(key).file_name().unwrap().to_str().unwrap();
+        let mut fs = FileState::new();
+        let before = names(&fs, dir).len();
+        let fd = fs.open_as_fd(&abs(key), libc::O_WRONLY, 0).unwrap();
+        close(&mut fs, fd);
+        assert_eq!(names(&fs, dir).iter().filter(|n| *n == name).count(), 1);
+        assert_eq!(names(&fs, dir).len(), before);
+        assert_eq!(fs.mkdir(&abs(&join_relpath(dir, "pyinrs-test-dir")), 0o755), Ok(()));
+        assert_eq!(names(&fs, dir).len(), before + 1);
+        assert!(fs.list_dir(dir).contains(&(String::from("pyinrs-test-dir"), true)));
     }
-    __real_get_current_dir_name()
 }